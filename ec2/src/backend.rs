@@ -0,0 +1,90 @@
+//Abstractions over the three places a request is written to.
+//The AWS-backed `S3`, `MySQL` and `DynamoDB` implement them, as do the stand-ins in `memory` and `local`.
+
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::color::Color;
+use super::error::Result;
+
+/*-------------------------------------*/
+
+#[async_trait]
+pub trait ObjectStorage: Send + Sync {
+    async fn upload(&self, filename: &str, image: Bytes, content_type: &str) -> Result<()>;
+
+    async fn exists(&self, filename: &str) -> Result<bool>;
+
+    async fn get_presigned_url(&self, filename: &str, expiration_secs: u32) -> Result<String>;
+
+    //Deleting an object which doesn't exist is not an error.
+    async fn delete(&self, filename: &str) -> Result<()>;
+}
+
+/*-------------------------------------*/
+
+//Records only the RGB part of a color, as the table has no column for alpha.
+//`select_by_color()` matches by RGB and returns opaque colors.
+#[async_trait]
+pub trait RelationalLog: Send + Sync {
+    async fn insert(&self, color: &Color) -> Result<()>;
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>>;
+}
+
+/*-------------------------------------*/
+
+#[async_trait]
+pub trait KeyValueLog: Send + Sync {
+    async fn insert(&self, color: &Color) -> Result<()>;
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>>;
+
+    //The colors inserted in `[from, to)`, oldest first.
+    async fn select_by_time_range(&self, from: SystemTime, to: SystemTime) -> Result<Vec<Color>>;
+}
+
+/*-------------------------------------*/
+
+//A response recorded under an idempotency key, replayed as is when the key is repeated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reservation {
+    //The key is new (or its previous use has expired) and is now reserved by the caller.
+    New,
+    //Another request with the key is still being processed.
+    InProgress,
+    //The key has been used for a request with the fingerprint and the response.
+    Done {
+        fingerprint: String,
+        response: StoredResponse,
+    },
+}
+
+//Remembers the `Idempotency-Key`s of requests for `window_sec` seconds.
+#[async_trait]
+pub trait IdempotencyStore: Send + Sync {
+    //Reserves `key` atomically unless it is already in use.
+    //`fingerprint` identifies the request body so that a key reused for another request can be detected.
+    async fn reserve(&self, key: &str, fingerprint: &str, window_sec: u64) -> Result<Reservation>;
+
+    async fn complete(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        response: &StoredResponse,
+        window_sec: u64,
+    ) -> Result<()>;
+
+    //Frees a reserved key so that the request can be retried.
+    async fn release(&self, key: &str) -> Result<()>;
+}
+
+/*-------------------------------------*/
@@ -0,0 +1,60 @@
+//Runs blocking code (e.g. the synchronous `mysql` driver) without blocking the tokio runtime.
+
+use std::sync::Arc;
+
+use tokio::sync::Semaphore;
+
+//Runs closures on tokio's blocking thread pool, at most `limit` at a time.
+//Callers beyond the limit wait asynchronously, so they don't occupy a thread either.
+#[derive(Clone)]
+pub struct BlockingPool {
+    semaphore: Arc<Semaphore>,
+}
+
+impl BlockingPool {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+        }
+    }
+
+    pub async fn run<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let _permit = self.semaphore.acquire().await.unwrap();
+        match tokio::task::spawn_blocking(f).await {
+            Ok(ret) => ret,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn sleep() {
+        std::thread::sleep(Duration::from_millis(200));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test01() {
+        let pool = BlockingPool::new(2);
+        let start = Instant::now();
+        tokio::join!(pool.run(sleep), pool.run(sleep));
+        assert!(start.elapsed() < Duration::from_millis(350));
+
+        let pool = BlockingPool::new(1);
+        let start = Instant::now();
+        tokio::join!(pool.run(sleep), pool.run(sleep));
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+}
+
+/*-------------------------------------*/
@@ -0,0 +1,224 @@
+//Circuit breakers around the backends.
+//After `failure_threshold` consecutive failures, the circuit opens and calls fail immediately with
+//`Error::CircuitOpen` for `open_ms` milliseconds. Then a single trial call is let through (half-open):
+//the circuit closes if it succeeds and opens again if it fails.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{info, warn};
+
+use super::backend::{KeyValueLog, ObjectStorage, RelationalLog};
+use super::color::Color;
+use super::config::CircuitBreakerConfig;
+use super::error::{Error, Result};
+
+/*-------------------------------------*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    //A trial call started at `since` is in progress.
+    HalfOpen { since: Instant },
+}
+
+pub struct CircuitBreaker {
+    name: String,
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            failure_threshold: config.failure_threshold,
+            open_duration: Duration::from_millis(config.open_ms),
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.lock().unwrap(), State::Closed { .. })
+    }
+
+    //Whether a call may go through now.
+    fn acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                info!("{}: circuit half-open, trying a call", self.name);
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            //If the trial call never finishes (e.g. its request is cancelled), another one is let through.
+            State::HalfOpen { since } if now >= since + self.open_duration => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            info!("{}: circuit closed", self.name);
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    fn on_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::HalfOpen { .. } => self.failure_threshold,
+            State::Open { .. } => return,
+        };
+        *state = if failures >= self.failure_threshold {
+            warn!(
+                "{}: circuit opened for {:?} after {} consecutive failure(s)",
+                self.name, self.open_duration, failures
+            );
+            State::Open {
+                until: Instant::now() + self.open_duration,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+
+    async fn call<T>(&self, f: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+        if !self.acquire() {
+            return Err(Error::circuit_open(&self.name));
+        }
+        let res = f.await;
+        match &res {
+            Ok(_) => self.on_success(),
+            Err(_) => self.on_failure(),
+        }
+        res
+    }
+}
+
+/*-------------------------------------*/
+
+pub struct Breaking<B> {
+    inner: B,
+    breaker: CircuitBreaker,
+}
+
+impl<B> Breaking<B> {
+    pub fn new(name: &str, inner: B, config: &CircuitBreakerConfig) -> Self {
+        Self {
+            inner,
+            breaker: CircuitBreaker::new(name, config),
+        }
+    }
+}
+
+#[async_trait]
+impl<B: ObjectStorage> ObjectStorage for Breaking<B> {
+    async fn upload(&self, filename: &str, image: Bytes, content_type: &str) -> Result<()> {
+        self.breaker
+            .call(self.inner.upload(filename, image, content_type))
+            .await
+    }
+
+    async fn exists(&self, filename: &str) -> Result<bool> {
+        self.breaker.call(self.inner.exists(filename)).await
+    }
+
+    async fn get_presigned_url(&self, filename: &str, expiration_secs: u32) -> Result<String> {
+        self.breaker
+            .call(self.inner.get_presigned_url(filename, expiration_secs))
+            .await
+    }
+
+    async fn delete(&self, filename: &str) -> Result<()> {
+        self.breaker.call(self.inner.delete(filename)).await
+    }
+}
+
+#[async_trait]
+impl<B: RelationalLog> RelationalLog for Breaking<B> {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        self.breaker.call(self.inner.insert(color)).await
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        self.breaker.call(self.inner.select_by_color(color)).await
+    }
+}
+
+#[async_trait]
+impl<B: KeyValueLog> KeyValueLog for Breaking<B> {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        self.breaker.call(self.inner.insert(color)).await
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        self.breaker.call(self.inner.select_by_color(color)).await
+    }
+
+    async fn select_by_time_range(&self, from: SystemTime, to: SystemTime) -> Result<Vec<Color>> {
+        self.breaker
+            .call(self.inner.select_by_time_range(from, to))
+            .await
+    }
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test01() {
+        let breaker = CircuitBreaker::new(
+            "rds",
+            &CircuitBreakerConfig {
+                failure_threshold: 2,
+                open_ms: 50,
+            },
+        );
+        let ok = || async { Ok(()) };
+        let fail = || async { Err::<(), _>(Error::rds("insert failed", "connection refused")) };
+
+        //A success resets the count of consecutive failures.
+        assert!(breaker.call(fail()).await.is_err());
+        assert!(breaker.call(ok()).await.is_ok());
+        assert!(breaker.call(fail()).await.is_err());
+        assert!(!breaker.is_open());
+        assert!(breaker.call(fail()).await.is_err());
+        assert!(breaker.is_open());
+
+        //While open, calls fail without being made.
+        let e = breaker.call(ok()).await.unwrap_err();
+        assert_eq!("circuit_open", e.code());
+        assert_eq!(
+            "rds is unavailable as its circuit breaker is open",
+            e.to_string()
+        );
+
+        //A failed trial opens the circuit again at once.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!("rds_failed", breaker.call(fail()).await.unwrap_err().code());
+        assert_eq!("circuit_open", breaker.call(ok()).await.unwrap_err().code());
+
+        //A successful trial closes it.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(breaker.call(ok()).await.is_ok());
+        assert!(!breaker.is_open());
+        assert!(breaker.call(ok()).await.is_ok());
+    }
+}
+
+/*-------------------------------------*/
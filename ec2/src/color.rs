@@ -1,12 +1,552 @@
-#[derive(Debug)]
+//Colors and their text representations.
+//`Color::from_str()` accepts hex (`#rgb`, `#rgba`, `#rrggbb`, `#rrggbbaa`), CSS named colors (and `transparent`),
+//and `hsl()`/`hsla()`/`hsv()`/`hsva()` with either of the syntaxes `hsl(30, 100%, 50%, 0.5)` and `hsl(30 100% 50% / 50%)`.
+//Hue is in degrees, and alpha is either a number from 0 to 1 or a percentage.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::error::{Error, Result};
+
+/*-------------------------------------*/
+
+const OPAQUE: u8 = u8::MAX;
+
+fn opaque() -> u8 {
+    OPAQUE
+}
+
+fn is_opaque(a: &u8) -> bool {
+    *a == OPAQUE
+}
+
+//`a` is omitted when serialized if the color is opaque, so opaque colors look the same as before alpha was introduced.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Color {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    #[serde(default = "opaque", skip_serializing_if = "is_opaque")]
+    pub a: u8,
 }
 
 impl Color {
     pub fn new(r: u8, g: u8, b: u8) -> Self {
-        Self { r, g, b }
+        Self::with_alpha(r, g, b, OPAQUE)
+    }
+
+    pub fn with_alpha(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        is_opaque(&self.a)
+    }
+
+    //The same color without transparency.
+    pub fn to_opaque(&self) -> Self {
+        Self::new(self.r, self.g, self.b)
+    }
+
+    //e.g. `ff8000`, or `ff800080` if the color is not opaque.
+    pub fn to_hex(&self) -> String {
+        let mut ret = format!("{:02x}{:02x}{:02x}", self.r, self.g, self.b);
+        if !self.is_opaque() {
+            ret.push_str(&format!("{:02x}", self.a));
+        }
+        ret
+    }
+
+    //The CSS name of an opaque color if it has one, e.g. `orange`.
+    pub fn name(&self) -> Option<&'static str> {
+        if !self.is_opaque() {
+            return None;
+        }
+        let rgb = u32::from_be_bytes([0, self.r, self.g, self.b]);
+        NAMED_COLORS
+            .iter()
+            .find(|(_, v)| *v == rgb)
+            .map(|(name, _)| *name)
+    }
+
+    //e.g. `hsl(30.1, 100%, 50%)` or `hsla(30.1, 100%, 50%, 0.502)`.
+    //Each component is rounded to a decimal place (and alpha to 3), which is still precise enough to be parsed back to the same color.
+    pub fn to_hsl(&self) -> String {
+        let (h, s, _, l) = self.hue_saturation();
+        self.format_cylindrical("hsl", h, s, l)
+    }
+
+    //e.g. `hsv(30, 100%, 100%)` or `hsva(30, 100%, 100%, 0.5)`, rounded in the same way as `to_hsl()`.
+    pub fn to_hsv(&self) -> String {
+        let (h, _, s, _) = self.hue_saturation();
+        let v = self.r.max(self.g).max(self.b) as f64 / 255.0;
+        self.format_cylindrical("hsv", h, s, v)
+    }
+
+    //Returns hue in degrees, saturation of HSL, saturation of HSV and lightness.
+    fn hue_saturation(&self) -> (f64, f64, f64, f64) {
+        let [r, g, b] = [self.r, self.g, self.b].map(|c| c as f64 / 255.0);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let c = max - min;
+        let h = if c == 0.0 {
+            0.0
+        } else if max == r {
+            60.0 * ((g - b) / c).rem_euclid(6.0)
+        } else if max == g {
+            60.0 * ((b - r) / c + 2.0)
+        } else {
+            60.0 * ((r - g) / c + 4.0)
+        };
+        let l = (max + min) / 2.0;
+        let s_hsl = if l == 0.0 || l == 1.0 {
+            0.0
+        } else {
+            c / (1.0 - (2.0 * l - 1.0).abs())
+        };
+        let s_hsv = if max == 0.0 { 0.0 } else { c / max };
+        (h, s_hsl, s_hsv, l)
+    }
+
+    fn format_cylindrical(&self, name: &str, h: f64, s: f64, x: f64) -> String {
+        let round = |x: f64| (x * 10.0).round() / 10.0;
+        let h = round(h) % 360.0;
+        let s = round(s * 100.0);
+        let x = round(x * 100.0);
+        if self.is_opaque() {
+            format!("{}({}, {}%, {}%)", name, h, s, x)
+        } else {
+            let a = (self.a as f64 / 255.0 * 1000.0).round() / 1000.0;
+            format!("{}a({}, {}%, {}%, {})", name, h, s, x, a)
+        }
+    }
+}
+
+//`#rrggbb`, or `#rrggbbaa` if the color is not opaque.
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.to_hex())
     }
 }
+
+impl FromStr for Color {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let parsed = if let Some(hex) = s.strip_prefix('#') {
+            parse_hex(hex)
+        } else if let Some(open) = s.find('(') {
+            parse_function(&s[..open].trim().to_ascii_lowercase(), &s[open + 1..])
+        } else {
+            parse_name(&s.to_ascii_lowercase())
+        };
+        parsed.map_err(|e| Error::invalid_request(format!("invalid color `{}`", s), e))
+    }
+}
+
+/*-------------------------------------*/
+
+fn parse_hex(hex: &str) -> std::result::Result<Color, String> {
+    if !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err("hex must consist of hexadecimal digits".to_string());
+    }
+    let digit = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).unwrap();
+    let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    match hex.len() {
+        3 | 4 => {
+            let [r, g, b] = [0, 1, 2].map(|i| digit(i) * 0x11);
+            let a = if hex.len() == 4 {
+                digit(3) * 0x11
+            } else {
+                OPAQUE
+            };
+            Ok(Color::with_alpha(r, g, b, a))
+        }
+        6 | 8 => {
+            let [r, g, b] = [0, 2, 4].map(byte);
+            let a = if hex.len() == 8 { byte(6) } else { OPAQUE };
+            Ok(Color::with_alpha(r, g, b, a))
+        }
+        _ => Err("hex must have 3, 4, 6 or 8 digits".to_string()),
+    }
+}
+
+fn parse_name(name: &str) -> std::result::Result<Color, String> {
+    if name == "transparent" {
+        return Ok(Color::with_alpha(0, 0, 0, 0));
+    }
+    NAMED_COLORS
+        .binary_search_by_key(&name, |(name, _)| name)
+        .map(|i| {
+            let [_, r, g, b] = NAMED_COLORS[i].1.to_be_bytes();
+            Color::new(r, g, b)
+        })
+        .map_err(|_| "unknown color name".to_string())
+}
+
+//Parses the arguments of `hsl()` and the like, after the opening parenthesis.
+fn parse_function(name: &str, args: &str) -> std::result::Result<Color, String> {
+    let to_rgb = match name {
+        "hsl" | "hsla" => hsl_to_rgb,
+        "hsv" | "hsva" => hsv_to_rgb,
+        _ => return Err(format!("unknown function `{}`", name)),
+    };
+    let args = args
+        .strip_suffix(')')
+        .ok_or_else(|| "missing `)`".to_string())?;
+
+    //Either `h, s, l[, a]` or `h s l[ / a]`.
+    let (args, alpha) = if args.contains(',') {
+        let mut args = args.split(',').map(str::trim).collect::<Vec<_>>();
+        let alpha = if args.len() == 4 { args.pop() } else { None };
+        (args, alpha)
+    } else {
+        let (args, alpha) = match args.split_once('/') {
+            Some((args, alpha)) => (args, Some(alpha.trim())),
+            None => (args, None),
+        };
+        (args.split_whitespace().collect(), alpha)
+    };
+    if args.len() != 3 {
+        return Err(format!(
+            "`{}()` takes 3 components and an optional alpha",
+            name
+        ));
+    }
+
+    let h = parse_number(args[0].strip_suffix("deg").unwrap_or(args[0]))?.rem_euclid(360.0);
+    let s = parse_percentage(args[1])?;
+    let x = parse_percentage(args[2])?;
+    let a = match alpha {
+        Some(alpha) => parse_alpha(alpha)?,
+        None => 1.0,
+    };
+    let [r, g, b] = to_rgb(h, s, x).map(to_u8);
+    Ok(Color::with_alpha(r, g, b, to_u8(a)))
+}
+
+fn parse_number(s: &str) -> std::result::Result<f64, String> {
+    s.trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|n| n.is_finite())
+        .ok_or_else(|| format!("`{}` is not a number", s))
+}
+
+//`%` is optional, e.g. both `50%` and `50` mean a half.
+fn parse_percentage(s: &str) -> std::result::Result<f64, String> {
+    let n = parse_number(s.strip_suffix('%').unwrap_or(s))?;
+    if !(0.0..=100.0).contains(&n) {
+        return Err(format!("`{}` must be between 0% and 100%", s));
+    }
+    Ok(n / 100.0)
+}
+
+//Either a number from 0 to 1 or a percentage.
+fn parse_alpha(s: &str) -> std::result::Result<f64, String> {
+    if s.ends_with('%') {
+        return parse_percentage(s);
+    }
+    let n = parse_number(s)?;
+    if !(0.0..=1.0).contains(&n) {
+        return Err(format!("alpha `{}` must be between 0 and 1", s));
+    }
+    Ok(n)
+}
+
+fn to_u8(x: f64) -> u8 {
+    (x * 255.0).round() as u8
+}
+
+//Converts hue, chroma and the value to be added to each component into RGB from 0 to 1.
+fn chroma_to_rgb(h: f64, c: f64, m: f64) -> [f64; 3] {
+    let x = c * (1.0 - ((h / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let [r, g, b] = match (h / 60.0) as u32 {
+        0 => [c, x, 0.0],
+        1 => [x, c, 0.0],
+        2 => [0.0, c, x],
+        3 => [0.0, x, c],
+        4 => [x, 0.0, c],
+        _ => [c, 0.0, x],
+    };
+    [r + m, g + m, b + m]
+}
+
+fn hsl_to_rgb(h: f64, s: f64, l: f64) -> [f64; 3] {
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    chroma_to_rgb(h, c, l - c / 2.0)
+}
+
+fn hsv_to_rgb(h: f64, s: f64, v: f64) -> [f64; 3] {
+    let c = v * s;
+    chroma_to_rgb(h, c, v - c)
+}
+
+//Named colors of CSS (ref: |https://www.w3.org/TR/css-color-4/#named-colors|), sorted by name.
+const NAMED_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xf0f8ff),
+    ("antiquewhite", 0xfaebd7),
+    ("aqua", 0x00ffff),
+    ("aquamarine", 0x7fffd4),
+    ("azure", 0xf0ffff),
+    ("beige", 0xf5f5dc),
+    ("bisque", 0xffe4c4),
+    ("black", 0x000000),
+    ("blanchedalmond", 0xffebcd),
+    ("blue", 0x0000ff),
+    ("blueviolet", 0x8a2be2),
+    ("brown", 0xa52a2a),
+    ("burlywood", 0xdeb887),
+    ("cadetblue", 0x5f9ea0),
+    ("chartreuse", 0x7fff00),
+    ("chocolate", 0xd2691e),
+    ("coral", 0xff7f50),
+    ("cornflowerblue", 0x6495ed),
+    ("cornsilk", 0xfff8dc),
+    ("crimson", 0xdc143c),
+    ("cyan", 0x00ffff),
+    ("darkblue", 0x00008b),
+    ("darkcyan", 0x008b8b),
+    ("darkgoldenrod", 0xb8860b),
+    ("darkgray", 0xa9a9a9),
+    ("darkgreen", 0x006400),
+    ("darkgrey", 0xa9a9a9),
+    ("darkkhaki", 0xbdb76b),
+    ("darkmagenta", 0x8b008b),
+    ("darkolivegreen", 0x556b2f),
+    ("darkorange", 0xff8c00),
+    ("darkorchid", 0x9932cc),
+    ("darkred", 0x8b0000),
+    ("darksalmon", 0xe9967a),
+    ("darkseagreen", 0x8fbc8f),
+    ("darkslateblue", 0x483d8b),
+    ("darkslategray", 0x2f4f4f),
+    ("darkslategrey", 0x2f4f4f),
+    ("darkturquoise", 0x00ced1),
+    ("darkviolet", 0x9400d3),
+    ("deeppink", 0xff1493),
+    ("deepskyblue", 0x00bfff),
+    ("dimgray", 0x696969),
+    ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1e90ff),
+    ("firebrick", 0xb22222),
+    ("floralwhite", 0xfffaf0),
+    ("forestgreen", 0x228b22),
+    ("fuchsia", 0xff00ff),
+    ("gainsboro", 0xdcdcdc),
+    ("ghostwhite", 0xf8f8ff),
+    ("gold", 0xffd700),
+    ("goldenrod", 0xdaa520),
+    ("gray", 0x808080),
+    ("green", 0x008000),
+    ("greenyellow", 0xadff2f),
+    ("grey", 0x808080),
+    ("honeydew", 0xf0fff0),
+    ("hotpink", 0xff69b4),
+    ("indianred", 0xcd5c5c),
+    ("indigo", 0x4b0082),
+    ("ivory", 0xfffff0),
+    ("khaki", 0xf0e68c),
+    ("lavender", 0xe6e6fa),
+    ("lavenderblush", 0xfff0f5),
+    ("lawngreen", 0x7cfc00),
+    ("lemonchiffon", 0xfffacd),
+    ("lightblue", 0xadd8e6),
+    ("lightcoral", 0xf08080),
+    ("lightcyan", 0xe0ffff),
+    ("lightgoldenrodyellow", 0xfafad2),
+    ("lightgray", 0xd3d3d3),
+    ("lightgreen", 0x90ee90),
+    ("lightgrey", 0xd3d3d3),
+    ("lightpink", 0xffb6c1),
+    ("lightsalmon", 0xffa07a),
+    ("lightseagreen", 0x20b2aa),
+    ("lightskyblue", 0x87cefa),
+    ("lightslategray", 0x778899),
+    ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xb0c4de),
+    ("lightyellow", 0xffffe0),
+    ("lime", 0x00ff00),
+    ("limegreen", 0x32cd32),
+    ("linen", 0xfaf0e6),
+    ("magenta", 0xff00ff),
+    ("maroon", 0x800000),
+    ("mediumaquamarine", 0x66cdaa),
+    ("mediumblue", 0x0000cd),
+    ("mediumorchid", 0xba55d3),
+    ("mediumpurple", 0x9370db),
+    ("mediumseagreen", 0x3cb371),
+    ("mediumslateblue", 0x7b68ee),
+    ("mediumspringgreen", 0x00fa9a),
+    ("mediumturquoise", 0x48d1cc),
+    ("mediumvioletred", 0xc71585),
+    ("midnightblue", 0x191970),
+    ("mintcream", 0xf5fffa),
+    ("mistyrose", 0xffe4e1),
+    ("moccasin", 0xffe4b5),
+    ("navajowhite", 0xffdead),
+    ("navy", 0x000080),
+    ("oldlace", 0xfdf5e6),
+    ("olive", 0x808000),
+    ("olivedrab", 0x6b8e23),
+    ("orange", 0xffa500),
+    ("orangered", 0xff4500),
+    ("orchid", 0xda70d6),
+    ("palegoldenrod", 0xeee8aa),
+    ("palegreen", 0x98fb98),
+    ("paleturquoise", 0xafeeee),
+    ("palevioletred", 0xdb7093),
+    ("papayawhip", 0xffefd5),
+    ("peachpuff", 0xffdab9),
+    ("peru", 0xcd853f),
+    ("pink", 0xffc0cb),
+    ("plum", 0xdda0dd),
+    ("powderblue", 0xb0e0e6),
+    ("purple", 0x800080),
+    ("rebeccapurple", 0x663399),
+    ("red", 0xff0000),
+    ("rosybrown", 0xbc8f8f),
+    ("royalblue", 0x4169e1),
+    ("saddlebrown", 0x8b4513),
+    ("salmon", 0xfa8072),
+    ("sandybrown", 0xf4a460),
+    ("seagreen", 0x2e8b57),
+    ("seashell", 0xfff5ee),
+    ("sienna", 0xa0522d),
+    ("silver", 0xc0c0c0),
+    ("skyblue", 0x87ceeb),
+    ("slateblue", 0x6a5acd),
+    ("slategray", 0x708090),
+    ("slategrey", 0x708090),
+    ("snow", 0xfffafa),
+    ("springgreen", 0x00ff7f),
+    ("steelblue", 0x4682b4),
+    ("tan", 0xd2b48c),
+    ("teal", 0x008080),
+    ("thistle", 0xd8bfd8),
+    ("tomato", 0xff6347),
+    ("turquoise", 0x40e0d0),
+    ("violet", 0xee82ee),
+    ("wheat", 0xf5deb3),
+    ("white", 0xffffff),
+    ("whitesmoke", 0xf5f5f5),
+    ("yellow", 0xffff00),
+    ("yellowgreen", 0x9acd32),
+];
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01() -> Result<()> {
+        for (s, expected) in [
+            ("#ff8000", Color::new(255, 128, 0)),
+            ("#FF8000", Color::new(255, 128, 0)),
+            ("#f80", Color::new(255, 136, 0)),
+            ("#f808", Color::with_alpha(255, 136, 0, 136)),
+            ("#ff800080", Color::with_alpha(255, 128, 0, 128)),
+            (" Orange ", Color::new(255, 165, 0)),
+            ("rebeccapurple", Color::new(102, 51, 153)),
+            ("transparent", Color::with_alpha(0, 0, 0, 0)),
+            ("hsl(30, 100%, 50%)", Color::new(255, 128, 0)),
+            ("hsl(30deg 100% 50%)", Color::new(255, 128, 0)),
+            ("hsl(-330, 100, 50)", Color::new(255, 128, 0)),
+            (
+                "hsla(30, 100%, 50%, 0.5)",
+                Color::with_alpha(255, 128, 0, 128),
+            ),
+            (
+                "hsl(30 100% 50% / 50%)",
+                Color::with_alpha(255, 128, 0, 128),
+            ),
+            ("hsl(0, 0%, 100%)", Color::new(255, 255, 255)),
+            ("hsv(30, 100%, 100%)", Color::new(255, 128, 0)),
+            ("HSV(240 50% 50%)", Color::new(64, 64, 128)),
+            ("hsva(120, 100%, 50%, 0)", Color::with_alpha(0, 128, 0, 0)),
+        ] {
+            assert_eq!(expected, s.parse::<Color>()?, "{}", s);
+        }
+
+        for s in [
+            "",
+            "ff8000",
+            "#ff800",
+            "#gg8000",
+            "orangish",
+            "hsl(30, 100%)",
+            "hsl(30, 100%, 50%",
+            "hsl(30, 101%, 50%)",
+            "hsl(30, 100%, 50%, 2)",
+            "hsl(thirty, 100%, 50%)",
+            "rgb(255, 128, 0)",
+        ] {
+            let e = s.parse::<Color>().unwrap_err();
+            assert_eq!("invalid_request", e.code(), "{}", s);
+        }
+        assert_eq!(
+            "invalid request: invalid color `#ff800`",
+            "#ff800".parse::<Color>().unwrap_err().to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test02() -> Result<()> {
+        let color = Color::new(255, 128, 0);
+        assert_eq!("ff8000", color.to_hex());
+        assert_eq!("#ff8000", color.to_string());
+        assert_eq!("hsl(30.1, 100%, 50%)", color.to_hsl());
+        assert_eq!("hsv(30.1, 100%, 100%)", color.to_hsv());
+        assert_eq!(None, color.name());
+        assert_eq!(Some("aqua"), Color::new(0, 255, 255).name());
+
+        let color = Color::with_alpha(255, 128, 0, 128);
+        assert_eq!("ff800080", color.to_hex());
+        assert_eq!("#ff800080", color.to_string());
+        assert_eq!("hsla(30.1, 100%, 50%, 0.502)", color.to_hsl());
+        assert_eq!(Color::new(255, 128, 0), color.to_opaque());
+
+        //Every representation is parsed back to the same color.
+        for color in [
+            Color::new(255, 128, 0),
+            Color::new(0, 0, 0),
+            Color::new(102, 51, 153),
+            Color::with_alpha(1, 2, 3, 4),
+        ] {
+            assert_eq!(color, color.to_string().parse()?);
+        }
+        for &(name, _) in NAMED_COLORS {
+            let color = name.parse::<Color>()?;
+            assert_eq!(color, color.to_hsl().parse()?, "{}", name);
+            assert_eq!(color, color.to_hsv().parse()?, "{}", name);
+        }
+
+        assert_eq!(148, NAMED_COLORS.len());
+        assert!(NAMED_COLORS.windows(2).all(|w| w[0].0 < w[1].0));
+
+        //Alpha is omitted from JSON when opaque.
+        assert_eq!(
+            r#"{"r":255,"g":128,"b":0}"#,
+            serde_json::to_string(&Color::new(255, 128, 0)).unwrap()
+        );
+        assert_eq!(
+            Color::with_alpha(255, 128, 0, 0),
+            serde_json::from_str(r#"{"r":255,"g":128,"b":0,"a":0}"#).unwrap()
+        );
+        assert_eq!(
+            Color::new(255, 128, 0),
+            serde_json::from_str(r#"{"r":255,"g":128,"b":0}"#).unwrap()
+        );
+
+        Ok(())
+    }
+}
+
+/*-------------------------------------*/
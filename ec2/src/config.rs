@@ -1,42 +1,1165 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use log::warn;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::error::{BoxError, Error, Result};
+use super::lenient;
+
+//Every field can be overridden by an environment variable of the form `EC2_<FIELD>`.
+//Nested fields are joined by `__`, e.g. `EC2_RDS__PASSWORD` overrides `rds.password`.
+pub const ENV_PREFIX: &str = "EC2_";
+
+//Path of the config file. This is not treated as an override.
+pub const CONFIG_FILE_ENV: &str = "EC2_CONFIG_FILE";
+
+//Format of the config file (`json`, `yaml` or `toml`), which is otherwise guessed from the extension.
+//This is not treated as an override either.
+pub const CONFIG_FORMAT_ENV: &str = "EC2_CONFIG_FORMAT";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    //Guesses the format from the extension of the file, falling back to JSON.
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("yaml") | Some("yml") => Self::Yaml,
+            Some("toml") => Self::Toml,
+            _ => Self::Json,
+        }
+    }
+
+    fn parse(self, content: &str) -> std::result::Result<Value, BoxError> {
+        Ok(match self {
+            Self::Json => serde_json::from_str(content)?,
+            Self::Yaml => serde_yaml::from_str(content)?,
+            Self::Toml => toml::from_str(content)?,
+        })
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = Error;
 
-#[derive(Debug, Deserialize, Serialize)]
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            "toml" => Ok(Self::Toml),
+            _ => Err(Error::config(
+                format!("unknown config format `{}`", s),
+                "expected one of `json`, `yaml` and `toml`",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Config {
     pub port: u16,
     pub img_width: u32,
     pub img_height: u32,
+    //Limits of the size a request may ask for, which also apply to `img_width` and `img_height`.
+    #[serde(default = "default_max_img_dimension")]
+    pub max_img_dimension: u32,
+    #[serde(default = "default_max_img_pixels")]
+    pub max_img_pixels: u64,
     pub s3: S3Config,
     pub rds: RDSConfig,
     pub dynamodb: DynamoDBConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    #[serde(default)]
+    pub idempotency: IdempotencyConfig,
+    //Where images and logs are stored. `local` and `memory` need no AWS account, though the `s3`, `rds` and `dynamodb` sections are still required.
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub local: LocalConfig,
+    //Required as `Authorization: Bearer <token>` by the admin routes (`/v1/outbox`), which are disabled when omitted.
+    #[serde(default)]
+    pub admin_token: Option<Secret>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Aws,
+    //Files under `local.dir`.
+    Local,
+    //Nothing is persisted, not even the outbox.
+    Memory,
+}
+
+//Used only when `backend` is `local`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LocalConfig {
+    #[serde(default = "default_local_dir")]
+    pub dir: String,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_local_dir(),
+        }
+    }
+}
+
+fn default_local_dir() -> String {
+    "./local".to_string()
+}
+
+//A string which is never printed nor serialized, so that it doesn't end up in logs.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"***\"")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str("***")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct S3Config {
     pub bucket_name: String,
     pub expiration_sec: u32,
+
+    //When omitted, the region is taken from the AWS environment (e.g. `AWS_REGION` or `~/.aws/config`).
+    #[serde(default)]
+    pub region: Option<String>,
+
+    //Custom endpoint such as `http://localhost:9000` for MinIO or `http://localhost:4566` for LocalStack.
+    #[serde(default)]
+    pub endpoint_url: Option<String>,
+
+    //Uses `<endpoint>/<bucket>/<key>` instead of `<bucket>.<endpoint>/<key>`, which most S3-compatible servers require.
+    #[serde(default)]
+    pub force_path_style: bool,
+
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct RDSConfig {
     pub host: String,
     pub port: u16,
     pub user: String,
-    pub password: String,
+    pub password: Secret,
     pub database_name: String,
     pub table_name: String,
+
+    //Minimum and maximum numbers of connections kept in the connection pool.
+    #[serde(default = "default_pool_min")]
+    pub pool_min: usize,
+    #[serde(default = "default_pool_max")]
+    pub pool_max: usize,
+
+    //How long a request waits for a connection when all of them are in use.
+    #[serde(default = "default_pool_timeout_ms")]
+    pub pool_timeout_ms: u32,
+
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+fn default_pool_min() -> usize {
+    1
+}
+
+fn default_pool_max() -> usize {
+    10
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+fn default_pool_timeout_ms() -> u32 {
+    10_000
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct DynamoDBConfig {
     pub table_name: String,
+
+    //Creates the table with the expected schema (see `dynamodb::DynamoDB`) at startup if it doesn't exist.
+    #[serde(default)]
+    pub create_table: bool,
+
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+//How transient failures of a backend are retried (see `retry`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RetryConfig {
+    //Including the first attempt, so `1` disables retrying.
+    #[serde(default = "default_retry_max_attempts")]
+    pub max_attempts: u32,
+
+    //The n-th retry waits for `base_delay_ms * 2^(n - 1)` milliseconds, capped at `max_delay_ms`.
+    #[serde(default = "default_base_delay_ms")]
+    pub base_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+
+    //Waits for a random duration between zero and the delay above instead,
+    //so that requests failed at the same time don't retry at the same time.
+    #[serde(default = "default_jitter")]
+    pub jitter: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_retry_max_attempts(),
+            base_delay_ms: default_base_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+//When a backend keeps failing, it is skipped for a while instead of making every request wait for it (see `breaker`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    //The circuit opens after this many consecutive failures.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+
+    //How long the circuit stays open before a trial call is let through.
+    #[serde(default = "default_open_ms")]
+    pub open_ms: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: default_failure_threshold(),
+            open_ms: default_open_ms(),
+        }
+    }
+}
+
+fn default_max_img_dimension() -> u32 {
+    4096
+}
+
+fn default_max_img_pixels() -> u64 {
+    4096 * 4096
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_open_ms() -> u64 {
+    30_000
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_base_delay_ms() -> u64 {
+    100
+}
+
+fn default_max_delay_ms() -> u64 {
+    2_000
+}
+
+fn default_jitter() -> bool {
+    true
+}
+
+//Where requests are recorded before being logged to RDS and DynamoDB (see `outbox`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OutboxConfig {
+    #[serde(default = "default_outbox_dir")]
+    pub dir: String,
+
+    //An entry is marked as failed once a sink has been tried this many times.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+
+    //How often pending entries are retried.
+    #[serde(default = "default_retry_interval_ms")]
+    pub retry_interval_ms: u64,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            dir: default_outbox_dir(),
+            max_attempts: default_max_attempts(),
+            retry_interval_ms: default_retry_interval_ms(),
+        }
+    }
+}
+
+fn default_outbox_dir() -> String {
+    "./outbox".to_string()
+}
+
+fn default_max_attempts() -> u32 {
+    10
+}
+
+fn default_retry_interval_ms() -> u64 {
+    5_000
+}
+
+//How requests with an `Idempotency-Key` header are deduplicated.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IdempotencyConfig {
+    //How long a key is remembered.
+    #[serde(default = "default_window_sec")]
+    pub window_sec: u64,
+
+    //DynamoDB table (with the partition key `key` of type string) where keys are stored.
+    //When omitted, keys are kept in memory, which is not shared with other servers nor survives a restart.
+    #[serde(default)]
+    pub table_name: Option<String>,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            window_sec: default_window_sec(),
+            table_name: None,
+        }
+    }
 }
 
-fn read_file(path: &str) -> String {
-    std::fs::read_to_string(path).unwrap()
+fn default_window_sec() -> u64 {
+    24 * 60 * 60
+}
+
+fn read_file(path: &str) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| Error::config(format!("failed to read {}", path), e))
+}
+
+//Sets `raw` to the field at `keys`, keeping the type of the value the field already has.
+fn set_field(value: &mut Value, keys: &[String], raw: &str) -> std::result::Result<(), String> {
+    let (last, parents) = keys.split_last().unwrap();
+    let mut cur = value;
+    for key in parents {
+        cur = cur
+            .as_object_mut()
+            .ok_or_else(|| format!("the parent of `{}` is not an object", key))?
+            .entry(key.clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+    let object = cur
+        .as_object_mut()
+        .ok_or_else(|| format!("the parent of `{}` is not an object", last))?;
+
+    let new_value = match object.get(last) {
+        Some(Value::String(_)) => Value::String(raw.to_string()),
+        Some(Value::Number(_)) => match serde_json::from_str(raw) {
+            Ok(Value::Number(n)) => Value::Number(n),
+            _ => return Err(format!("expected a number but got `{}`", raw)),
+        },
+        Some(Value::Bool(_)) => match raw {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => return Err(format!("expected `true` or `false` but got `{}`", raw)),
+        },
+        Some(Value::Object(_)) | Some(Value::Array(_)) => serde_json::from_str(raw)
+            .map_err(|e| format!("expected a JSON value but got `{}`: {}", raw, e))?,
+        //The field is absent from the file, so the value is kept as a string and converted by `lenient` to the type of the field.
+        //Guessing the type here would turn e.g. a password `12345` into a number.
+        Some(Value::Null) | None => Value::String(raw.to_string()),
+    };
+    object.insert(last.clone(), new_value);
+    Ok(())
+}
+
+//Returns the names of the variables applied, with the keys of their fields.
+fn apply_env_overrides(
+    value: &mut Value,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<Vec<(String, Vec<String>)>> {
+    let mut overrides = Vec::new();
+    for (name, raw) in vars {
+        if name == CONFIG_FILE_ENV || name == CONFIG_FORMAT_ENV {
+            continue;
+        }
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) => path,
+            None => continue,
+        };
+        let keys = path
+            .split("__")
+            .map(|k| k.to_lowercase())
+            .collect::<Vec<_>>();
+        if keys.iter().any(|k| k.is_empty()) {
+            return Err(Error::config(
+                format!("invalid environment variable {}", name),
+                "field names must be non-empty and separated by `__`",
+            ));
+        }
+        set_field(value, &keys, &raw)
+            .map_err(|e| Error::config(format!("invalid environment variable {}", name), e))?;
+        overrides.push((name, keys));
+    }
+    Ok(overrides)
+}
+
+//Whether `keys` names a field of `fields`, the serialized configuration.
+//Unknown fields are ignored when deserialized, so a misspelled variable would otherwise go unnoticed.
+fn is_field(fields: &Value, keys: &[String]) -> bool {
+    let mut cur = fields;
+    for key in keys {
+        cur = match cur {
+            Value::Object(m) => match m.get(key) {
+                Some(v) => v,
+                None => return false,
+            },
+            //An absent optional section, whose fields are unknown here.
+            Value::Null => return true,
+            _ => return false,
+        };
+    }
+    true
+}
+
+//A problem found by `Config::validate()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    //JSON path of the field, e.g. `$.s3.bucket_name`.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+//Every problem found by `Config::validate()`, used as the source of the returned error.
+#[derive(Debug)]
+pub struct ConfigIssues(pub Vec<ConfigIssue>);
+
+impl fmt::Display for ConfigIssues {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let issues = self.0.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", issues.join("; "))
+    }
+}
+
+impl std::error::Error for ConfigIssues {}
+
+//Presigned URLs of S3 can't live longer than 7 days.
+const MAX_EXPIRATION_SEC: u32 = 7 * 24 * 60 * 60;
+
+//Idempotency keys are meant for retries, which don't come a year later.
+pub const MAX_WINDOW_SEC: u64 = 365 * 24 * 60 * 60;
+
+//ref: |https://docs.aws.amazon.com/AmazonS3/latest/userguide/bucketnamingrules.html|
+fn check_bucket_name(name: &str) -> Option<String> {
+    if !(3..=63).contains(&name.len()) {
+        return Some("must be between 3 and 63 characters long".to_string());
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-')
+    {
+        return Some(
+            "must consist only of lowercase letters, digits, dots and hyphens".to_string(),
+        );
+    }
+    let is_alphanumeric = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    if !is_alphanumeric(name.chars().next()) || !is_alphanumeric(name.chars().last()) {
+        return Some("must begin and end with a letter or a digit".to_string());
+    }
+    None
+}
+
+//The table name of MySQL is embedded into SQL statements, so we only allow plain identifiers.
+fn check_sql_identifier(name: &str) -> Option<String> {
+    let mut chars = name.chars();
+    let valid = name.len() <= 64
+        && chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        None
+    } else {
+        Some(
+            "must be at most 64 characters of letters, digits and underscores, not starting with a digit"
+                .to_string(),
+        )
+    }
+}
+
+//ref: |https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/HowItWorks.NamingRulesDataTypes.html|
+fn check_dynamodb_table_name(name: &str) -> Option<String> {
+    if !(3..=255).contains(&name.len()) {
+        return Some("must be between 3 and 255 characters long".to_string());
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return Some(
+            "must consist only of letters, digits, underscores, hyphens and dots".to_string(),
+        );
+    }
+    None
+}
+
+fn check_not_empty(s: &str) -> Option<String> {
+    if s.is_empty() {
+        Some("must not be empty".to_string())
+    } else {
+        None
+    }
+}
+
+fn check_positive(n: u64) -> Option<String> {
+    if n == 0 {
+        Some("must be positive".to_string())
+    } else {
+        None
+    }
+}
+
+fn check_at_most(n: u64, max: u64, name: &str) -> Option<String> {
+    if n <= max {
+        None
+    } else {
+        Some(format!("must not be greater than `{}`", name))
+    }
 }
 
 impl Config {
-    pub fn new(config_file: &str) -> Self {
-        serde_json::from_str(&read_file(config_file)).unwrap()
+    //Reads the config file and applies the overrides in the environment variables of this process.
+    //The format is taken from `EC2_CONFIG_FORMAT` if set.
+    pub fn new(config_file: &str) -> Result<Self> {
+        let format = match std::env::var(CONFIG_FORMAT_ENV) {
+            Ok(format) => format.parse()?,
+            Err(_) => ConfigFormat::from_path(config_file),
+        };
+        Self::load_as(config_file, format, std::env::vars())
+    }
+
+    //Same as `load_as()` but guesses the format from the extension of the file.
+    pub fn load(
+        config_file: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        Self::load_as(config_file, ConfigFormat::from_path(config_file), vars)
+    }
+
+    pub fn load_as(
+        config_file: &str,
+        format: ConfigFormat,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let mut value = format
+            .parse(&read_file(config_file)?)
+            .map_err(|e| Error::config(format!("failed to parse {}", config_file), e))?;
+        let overrides = apply_env_overrides(&mut value, vars)?;
+        let config: Self = lenient::from_value(value)
+            .map_err(|e| Error::config(format!("invalid configuration in {}", config_file), e))?;
+        for name in config.unknown_overrides(&overrides) {
+            warn!(
+                "{} doesn't match any field of the configuration and is ignored",
+                name
+            );
+        }
+        Ok(config)
+    }
+
+    //The names of the variables which don't override any field.
+    fn unknown_overrides<'a>(&self, overrides: &'a [(String, Vec<String>)]) -> Vec<&'a str> {
+        let fields = serde_json::to_value(self).unwrap();
+        overrides
+            .iter()
+            .filter(|(_, keys)| !is_field(&fields, keys))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    //Serialized with the secrets redacted, e.g. to log the configuration in effect.
+    pub fn to_redacted_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    //Returns every problem of the configuration at once.
+    pub fn issues(&self) -> Vec<ConfigIssue> {
+        let mut checks = vec![
+            ("$.port", check_positive(self.port.into())),
+            ("$.img_width", check_positive(self.img_width.into())),
+            ("$.img_height", check_positive(self.img_height.into())),
+            (
+                "$.max_img_dimension",
+                check_positive(self.max_img_dimension.into()),
+            ),
+            ("$.max_img_pixels", check_positive(self.max_img_pixels)),
+            (
+                "$.img_width",
+                check_at_most(
+                    self.img_width.into(),
+                    self.max_img_dimension.into(),
+                    "max_img_dimension",
+                ),
+            ),
+            (
+                "$.img_height",
+                check_at_most(
+                    self.img_height.into(),
+                    self.max_img_dimension.into(),
+                    "max_img_dimension",
+                ),
+            ),
+            (
+                "$.img_height",
+                if u64::from(self.img_width) * u64::from(self.img_height) <= self.max_img_pixels {
+                    None
+                } else {
+                    Some(
+                        "`img_width` times `img_height` must not be greater than `max_img_pixels`"
+                            .to_string(),
+                    )
+                },
+            ),
+            ("$.s3.bucket_name", check_bucket_name(&self.s3.bucket_name)),
+            (
+                "$.s3.expiration_sec",
+                if (1..=MAX_EXPIRATION_SEC).contains(&self.s3.expiration_sec) {
+                    None
+                } else {
+                    Some(format!("must be between 1 and {}", MAX_EXPIRATION_SEC))
+                },
+            ),
+            ("$.rds.host", check_not_empty(&self.rds.host)),
+            ("$.rds.port", check_positive(self.rds.port.into())),
+            ("$.rds.user", check_not_empty(&self.rds.user)),
+            (
+                "$.rds.database_name",
+                check_not_empty(&self.rds.database_name),
+            ),
+            (
+                "$.rds.table_name",
+                check_sql_identifier(&self.rds.table_name),
+            ),
+            ("$.rds.pool_max", check_positive(self.rds.pool_max as u64)),
+            (
+                "$.rds.pool_min",
+                if self.rds.pool_min <= self.rds.pool_max {
+                    None
+                } else {
+                    Some("must not be greater than `pool_max`".to_string())
+                },
+            ),
+            (
+                "$.rds.pool_timeout_ms",
+                check_positive(self.rds.pool_timeout_ms.into()),
+            ),
+            (
+                "$.dynamodb.table_name",
+                check_dynamodb_table_name(&self.dynamodb.table_name),
+            ),
+            ("$.outbox.dir", check_not_empty(&self.outbox.dir)),
+            (
+                "$.outbox.max_attempts",
+                check_positive(self.outbox.max_attempts.into()),
+            ),
+            (
+                "$.outbox.retry_interval_ms",
+                check_positive(self.outbox.retry_interval_ms),
+            ),
+            (
+                "$.idempotency.window_sec",
+                if (1..=MAX_WINDOW_SEC).contains(&self.idempotency.window_sec) {
+                    None
+                } else {
+                    Some(format!("must be between 1 and {}", MAX_WINDOW_SEC))
+                },
+            ),
+        ];
+        if let Some(table_name) = &self.idempotency.table_name {
+            checks.push((
+                "$.idempotency.table_name",
+                check_dynamodb_table_name(table_name),
+            ));
+        }
+        if let Some(admin_token) = &self.admin_token {
+            checks.push(("$.admin_token", check_not_empty(admin_token.expose())));
+        }
+        if let Some(region) = &self.s3.region {
+            checks.push(("$.s3.region", check_not_empty(region)));
+        }
+        if let Some(endpoint_url) = &self.s3.endpoint_url {
+            checks.push((
+                "$.s3.endpoint_url",
+                if endpoint_url.starts_with("http://") || endpoint_url.starts_with("https://") {
+                    None
+                } else {
+                    Some("must start with `http://` or `https://`".to_string())
+                },
+            ));
+        }
+
+        let mut checks = checks
+            .into_iter()
+            .map(|(path, message)| (path.to_string(), message))
+            .collect::<Vec<_>>();
+        for (section, retry, circuit_breaker) in [
+            ("s3", &self.s3.retry, &self.s3.circuit_breaker),
+            ("rds", &self.rds.retry, &self.rds.circuit_breaker),
+            (
+                "dynamodb",
+                &self.dynamodb.retry,
+                &self.dynamodb.circuit_breaker,
+            ),
+        ] {
+            checks.push((
+                format!("$.{}.retry.max_attempts", section),
+                check_positive(retry.max_attempts.into()),
+            ));
+            checks.push((
+                format!("$.{}.retry.base_delay_ms", section),
+                if retry.base_delay_ms <= retry.max_delay_ms {
+                    None
+                } else {
+                    Some("must not be greater than `max_delay_ms`".to_string())
+                },
+            ));
+            checks.push((
+                format!("$.{}.circuit_breaker.failure_threshold", section),
+                check_positive(circuit_breaker.failure_threshold.into()),
+            ));
+            checks.push((
+                format!("$.{}.circuit_breaker.open_ms", section),
+                check_positive(circuit_breaker.open_ms),
+            ));
+        }
+
+        checks
+            .into_iter()
+            .filter_map(|(path, message)| message.map(|message| ConfigIssue { path, message }))
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let issues = self.issues();
+        if issues.is_empty() {
+            return Ok(());
+        }
+        Err(Error::config(
+            format!("{} problem(s) found", issues.len()),
+            ConfigIssues(issues),
+        ))
     }
 }
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+    use std::io::Write;
+
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "port": 30021,
+        "img_width": 300,
+        "img_height": 200,
+        "s3": {"bucket_name": "bucket", "expiration_sec": 30},
+        "rds": {
+            "host": "localhost",
+            "port": 3306,
+            "user": "admin",
+            "password": "abcde",
+            "database_name": "test",
+            "table_name": "colors"
+        },
+        "dynamodb": {"table_name": "colors"}
+    }"#;
+
+    const CONFIG_YAML: &str = r#"
+        # Comments are allowed.
+        port: 30021
+        img_width: 300
+        img_height: 200
+        s3:
+          bucket_name: bucket
+          expiration_sec: 30
+        rds:
+          host: localhost
+          port: 3306
+          user: admin
+          password: abcde
+          database_name: test
+          table_name: colors
+        dynamodb:
+          table_name: colors
+    "#;
+
+    const CONFIG_TOML: &str = r#"
+        # Comments are allowed.
+        port = 30021
+        img_width = 300
+        img_height = 200
+
+        [s3]
+        bucket_name = "bucket"
+        expiration_sec = 30
+
+        [rds]
+        host = "localhost"
+        port = 3306
+        user = "admin"
+        password = "abcde"
+        database_name = "test"
+        table_name = "colors"
+
+        [dynamodb]
+        table_name = "colors"
+    "#;
+
+    fn write_config(content: &str) -> tempfile::NamedTempFile {
+        write_config_with_suffix(content, ".json")
+    }
+
+    fn write_config_with_suffix(content: &str, suffix: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn vars(v: &[(&str, &str)]) -> Vec<(String, String)> {
+        v.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test01() {
+        let file = write_config(CONFIG);
+        let path = file.path().to_str().unwrap();
+
+        let config = Config::load(path, vec![]).unwrap();
+        assert_eq!(30021, config.port);
+        assert_eq!("abcde", config.rds.password.expose());
+
+        let config = Config::load(
+            path,
+            vars(&[
+                ("EC2_PORT", "8080"),
+                ("EC2_RDS__PASSWORD", "12345"),
+                ("EC2_S3__BUCKET_NAME", "another"),
+                ("EC2_S3__ENDPOINT_URL", "http://localhost:9000"),
+                ("EC2_S3__FORCE_PATH_STYLE", "true"),
+                ("EC2_CONFIG_FILE", "./other.json"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(8080, config.port);
+        assert_eq!("12345", config.rds.password.expose());
+        assert_eq!("another", config.s3.bucket_name);
+        assert_eq!(
+            Some("http://localhost:9000".to_string()),
+            config.s3.endpoint_url
+        );
+        assert!(config.s3.force_path_style);
+    }
+
+    #[test]
+    fn test02() {
+        let e = Config::load("./no_such_file.json", vec![]).unwrap_err();
+        assert_eq!(
+            "config error: failed to read ./no_such_file.json",
+            e.to_string()
+        );
+        assert!(e.source().is_some());
+
+        let file = write_config("{");
+        let path = file.path().to_str().unwrap();
+        let e = Config::load(path, vec![]).unwrap_err();
+        assert!(e.to_string().starts_with("config error: failed to parse "));
+
+        let file = write_config(CONFIG);
+        let path = file.path().to_str().unwrap();
+
+        let e = Config::load(path, vars(&[("EC2_PORT", "abc")])).unwrap_err();
+        assert_eq!(
+            "config error: invalid environment variable EC2_PORT",
+            e.to_string()
+        );
+        assert_eq!(
+            "expected a number but got `abc`",
+            e.source().unwrap().to_string()
+        );
+
+        let e = Config::load(path, vars(&[("EC2_PORT", "100000")])).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("config error: invalid configuration in "));
+
+        assert!(Config::load(path, vars(&[("EC2_RDS__", "x")])).is_err());
+        assert!(Config::load(path, vars(&[("EC2_PORT__X", "1")])).is_err());
+    }
+
+    #[test]
+    fn test03() {
+        let file = write_config(CONFIG);
+        let path = file.path().to_str().unwrap();
+        let config = Config::load(path, vec![]).unwrap();
+        assert!(config.validate().is_ok());
+
+        let config = Config::load(
+            path,
+            vars(&[
+                ("EC2_IMG_WIDTH", "0"),
+                ("EC2_S3__BUCKET_NAME", ""),
+                ("EC2_S3__EXPIRATION_SEC", "604801"),
+                ("EC2_S3__ENDPOINT_URL", "localhost:9000"),
+                ("EC2_RDS__TABLE_NAME", "colors; DROP TABLE colors"),
+                ("EC2_DYNAMODB__TABLE_NAME", "a/b"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "$.img_width",
+                "$.s3.bucket_name",
+                "$.s3.expiration_sec",
+                "$.rds.table_name",
+                "$.dynamodb.table_name",
+                "$.s3.endpoint_url",
+            ],
+            config
+                .issues()
+                .iter()
+                .map(|i| i.path.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let e = config.validate().unwrap_err();
+        assert_eq!("config error: 6 problem(s) found", e.to_string());
+        let issues = e.source().unwrap().downcast_ref::<ConfigIssues>().unwrap();
+        assert_eq!(6, issues.0.len());
+        assert!(issues
+            .to_string()
+            .contains("$.s3.expiration_sec: must be between 1 and 604800"));
+
+        let config = Config::load(path, vars(&[("EC2_RDS__POOL_MIN", "20")])).unwrap();
+        assert_eq!(10, config.rds.pool_max);
+        assert_eq!(
+            vec!["$.rds.pool_min: must not be greater than `pool_max`".to_string()],
+            config
+                .issues()
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+        );
+
+        let config = Config::load(
+            path,
+            vars(&[
+                ("EC2_DYNAMODB__RETRY__MAX_ATTEMPTS", "0"),
+                ("EC2_DYNAMODB__RETRY__BASE_DELAY_MS", "5000"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(RetryConfig::default(), config.s3.retry);
+        assert_eq!(
+            vec![
+                "$.dynamodb.retry.max_attempts: must be positive".to_string(),
+                "$.dynamodb.retry.base_delay_ms: must not be greater than `max_delay_ms`"
+                    .to_string(),
+            ],
+            config
+                .issues()
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(4096, config.max_img_dimension);
+        assert_eq!(4096 * 4096, config.max_img_pixels);
+        let config = Config::load(
+            path,
+            vars(&[
+                ("EC2_MAX_IMG_DIMENSION", "250"),
+                ("EC2_MAX_IMG_PIXELS", "50000"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "$.img_width: must not be greater than `max_img_dimension`".to_string(),
+                "$.img_height: `img_width` times `img_height` must not be greater than `max_img_pixels`"
+                    .to_string(),
+            ],
+            config
+                .issues()
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+        );
+
+        let config = Config::load(
+            path,
+            vars(&[("EC2_IDEMPOTENCY__WINDOW_SEC", "18446744073709551615")]),
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["$.idempotency.window_sec: must be between 1 and 31536000".to_string()],
+            config
+                .issues()
+                .iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+        );
+
+        assert!(check_bucket_name("bucket-test-002-a").is_none());
+        assert!(check_bucket_name("Bucket").is_some());
+        assert!(check_bucket_name("-bucket").is_some());
+        assert!(check_sql_identifier("colors_2").is_none());
+        assert!(check_sql_identifier("2colors").is_some());
+    }
+
+    #[test]
+    fn test04() {
+        let json = write_config(CONFIG);
+        let yaml = write_config_with_suffix(CONFIG_YAML, ".yaml");
+        let yml = write_config_with_suffix(CONFIG_YAML, ".yml");
+        let toml = write_config_with_suffix(CONFIG_TOML, ".toml");
+        let toml_without_extension = write_config_with_suffix(CONFIG_TOML, "");
+
+        let expected = Config::load(json.path().to_str().unwrap(), vec![]).unwrap();
+        for file in [&yaml, &yml, &toml] {
+            assert_eq!(
+                expected,
+                Config::load(file.path().to_str().unwrap(), vec![]).unwrap()
+            );
+        }
+
+        let path = toml_without_extension.path().to_str().unwrap();
+        assert!(Config::load(path, vec![]).is_err());
+        assert_eq!(
+            expected,
+            Config::load_as(path, "TOML".parse().unwrap(), vec![]).unwrap()
+        );
+
+        //Overrides keep working regardless of the format.
+        let config = Config::load(
+            yaml.path().to_str().unwrap(),
+            vars(&[
+                ("EC2_RDS__PASSWORD", "12345"),
+                ("EC2_CONFIG_FORMAT", "yaml"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!("12345", config.rds.password.expose());
+
+        assert!("ini".parse::<ConfigFormat>().is_err());
+        assert_eq!(ConfigFormat::Json, ConfigFormat::from_path("./config"));
+    }
+
+    #[test]
+    fn test05() {
+        //The password is left to `EC2_RDS__PASSWORD`.
+        let file = write_config(&CONFIG.replace(r#""password": "abcde","#, ""));
+        let path = file.path().to_str().unwrap();
+        for password in ["12345", "true", "null", "{}"] {
+            let config = Config::load(path, vars(&[("EC2_RDS__PASSWORD", password)])).unwrap();
+            assert_eq!(password, config.rds.password.expose());
+        }
+
+        //Values for other missing fields are still converted to their types.
+        let config = Config::load(
+            path,
+            vars(&[
+                ("EC2_RDS__PASSWORD", "12345"),
+                ("EC2_S3__FORCE_PATH_STYLE", "true"),
+                ("EC2_S3__REGION", "us-east-1"),
+                ("EC2_BACKEND", "memory"),
+            ]),
+        )
+        .unwrap();
+        assert!(config.s3.force_path_style);
+        assert_eq!(Some("us-east-1".to_string()), config.s3.region);
+        assert_eq!(Backend::Memory, config.backend);
+        assert!(Config::load(
+            path,
+            vars(&[
+                ("EC2_RDS__PASSWORD", "12345"),
+                ("EC2_MAX_IMG_DIMENSION", "large")
+            ])
+        )
+        .is_err());
+
+        //The password never appears in logs.
+        assert!(!format!("{:?}", config).contains("12345"));
+        assert!(!config.to_redacted_json().contains("12345"));
+        assert!(format!("{:?}", config.rds).contains(r#"password: "***""#));
+    }
+
+    #[test]
+    fn test06() {
+        let mut value: Value = serde_json::from_str(CONFIG).unwrap();
+        let overrides = apply_env_overrides(
+            &mut value,
+            vars(&[
+                ("EC2_PORT", "8081"),
+                ("EC2_PROT", "8081"),
+                ("EC2_S3__BUCKET", "bucket"),
+                ("EC2_S3__REGION", "us-east-1"),
+                ("EC2_ADMIN_TOKEN", "token"),
+                ("EC2_LOCAL__DIR", "./dir"),
+            ]),
+        )
+        .unwrap();
+        let config: Config = lenient::from_value(value).unwrap();
+        assert_eq!(
+            vec!["EC2_PROT", "EC2_S3__BUCKET"],
+            config.unknown_overrides(&overrides)
+        );
+
+        //Misspelled variables are only warned about.
+        let file = write_config(CONFIG);
+        assert!(Config::load(file.path().to_str().unwrap(), vars(&[("EC2_PROT", "8081")])).is_ok());
+    }
+}
+
+/*-------------------------------------*/
@@ -1,74 +1,522 @@
-#[cfg(test)]
 use std::collections::HashMap;
-use std::error::Error;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
-use aws_sdk_dynamodb::model::AttributeValue;
-#[cfg(test)]
+use async_trait::async_trait;
+use aws_sdk_dynamodb::client::fluent_builders::Query;
+use aws_sdk_dynamodb::config::retry::RetryConfig;
+use aws_sdk_dynamodb::model::{
+    AttributeDefinition, AttributeValue, BillingMode, GlobalSecondaryIndex, IndexStatus,
+    KeySchemaElement, KeyType, Projection, ProjectionType, ScalarAttributeType, TableDescription,
+    TableStatus,
+};
+use aws_sdk_dynamodb::types::SdkError;
+use aws_smithy_types::retry::ProvideErrorKind;
+use log::info;
+use time::{format_description::FormatItem, macros::format_description, Date, OffsetDateTime};
 use tokio_stream::StreamExt;
 
+use super::backend::{IdempotencyStore, KeyValueLog, Reservation, StoredResponse};
 use super::color::Color;
 use super::config::DynamoDBConfig;
+use super::error::{BoxError, Error, Result};
+use super::retry::is_retryable_sdk_error;
 
+//Error codes of DynamoDB worth retrying besides those the SDK models as retryable.
+const RETRYABLE_CODES: &[&str] = &[
+    "ProvisionedThroughputExceededException",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "InternalServerError",
+    "TransactionConflictException",
+];
+
+fn dynamodb_error<E>(message: &str, e: SdkError<E>) -> Error
+where
+    E: ProvideErrorKind + std::error::Error + Send + Sync + 'static,
+{
+    let retryable = is_retryable_sdk_error(&e, RETRYABLE_CODES);
+    Error::dynamodb(message, e).retryable(retryable)
+}
+
+//Items are of the form `{color, id, day, created_at, r, g, b}`.
+//The table is partitioned by `color` (e.g. `ff8000`) and sorted by `id` (a ULID, so by time) within a color.
+//The global secondary index `by_time` partitions the items by `day` (the UTC date, e.g. `2023-03-16`)
+//and sorts them by `created_at` (epoch millis), so that a time range is read a day at a time.
 pub struct DynamoDB {
     table_name: String,
     client: aws_sdk_dynamodb::Client,
 }
 
-impl DynamoDB {
-    pub async fn new(dynamodb_config: &DynamoDBConfig) -> Result<Self, Box<dyn Error>> {
-        let config = aws_config::load_from_env().await;
-        let client = aws_sdk_dynamodb::Client::new(&config);
+const INDEX_NAME: &str = "by_time";
 
-        Ok(Self {
-            table_name: dynamodb_config.table_name.clone(),
-            client,
+//`(attribute, key type, attribute type)` of the keys of the table and of the index.
+const TABLE_KEYS: &[(&str, &str, &str)] = &[("color", "HASH", "S"), ("id", "RANGE", "S")];
+const INDEX_KEYS: &[(&str, &str, &str)] = &[("day", "HASH", "S"), ("created_at", "RANGE", "N")];
+
+//How long to wait for a table created at startup to become active.
+const TABLE_CREATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+const DAY_FORMAT: &[FormatItem<'static>] = format_description!("[year]-[month]-[day]");
+
+async fn new_client() -> aws_sdk_dynamodb::Client {
+    let config = aws_config::load_from_env().await;
+    aws_sdk_dynamodb::Client::from_conf(
+        aws_sdk_dynamodb::config::Builder::from(&config)
+            //Retried by `retry::Retrying` instead.
+            .retry_config(RetryConfig::disabled())
+            .build(),
+    )
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as u64
+}
+
+//`OffsetDateTime::from()` panics for times it can't represent, e.g. after the year 9999.
+fn date_of(time: SystemTime) -> Result<Date> {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(millis(time)) * 1_000_000)
+        .map(|t| t.date())
+        .map_err(|e| Error::invalid_request("invalid range", e))
+}
+
+fn day_of(date: Date) -> String {
+    date.format(DAY_FORMAT).unwrap()
+}
+
+fn describe_keys(keys: &[(String, String, String)]) -> String {
+    keys.iter()
+        .map(|(name, key_type, attribute_type)| {
+            format!("{} ({}, {})", name, key_type, attribute_type)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+//Lists how the schema of `table` differs from the one `DynamoDB` expects.
+fn schema_issues(table: &TableDescription) -> Vec<String> {
+    let attribute_type = |name: &str| {
+        table
+            .attribute_definitions()
+            .unwrap_or_default()
+            .iter()
+            .find(|a| a.attribute_name() == Some(name))
+            .and_then(|a| a.attribute_type())
+            .map_or("", |t| t.as_str())
+            .to_string()
+    };
+    let check_keys = |what: &str,
+                      actual: &[KeySchemaElement],
+                      expected: &[(&str, &str, &str)]|
+     -> Option<String> {
+        let actual = actual
+            .iter()
+            .map(|k| {
+                let name = k.attribute_name().unwrap_or_default();
+                (
+                    name.to_string(),
+                    k.key_type().map_or("", |t| t.as_str()).to_string(),
+                    attribute_type(name),
+                )
+            })
+            .collect::<Vec<_>>();
+        let expected = expected
+            .iter()
+            .map(|(a, b, c)| (a.to_string(), b.to_string(), c.to_string()))
+            .collect::<Vec<_>>();
+        (actual != expected).then(|| {
+            format!(
+                "the keys of {} must be {} but are {}",
+                what,
+                describe_keys(&expected),
+                describe_keys(&actual)
+            )
         })
+    };
+
+    let mut ret = vec![];
+    ret.extend(check_keys(
+        "the table",
+        table.key_schema().unwrap_or_default(),
+        TABLE_KEYS,
+    ));
+    let index = table
+        .global_secondary_indexes()
+        .unwrap_or_default()
+        .iter()
+        .find(|index| index.index_name() == Some(INDEX_NAME));
+    match index {
+        None => ret.push(format!(
+            "the global secondary index `{}` is missing",
+            INDEX_NAME
+        )),
+        Some(index) => {
+            ret.extend(check_keys(
+                &format!("the index `{}`", INDEX_NAME),
+                index.key_schema().unwrap_or_default(),
+                INDEX_KEYS,
+            ));
+            if index.projection().and_then(|p| p.projection_type()) != Some(&ProjectionType::All) {
+                ret.push(format!(
+                    "the index `{}` must project all the attributes",
+                    INDEX_NAME
+                ));
+            }
+        }
     }
+    ret
+}
 
-    pub async fn insert(&self, color: &Color) -> Result<(), Box<dyn Error>> {
-        let timestamp = AttributeValue::S(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis()
-                .to_string(),
-        );
-        let r = AttributeValue::N(color.r.to_string());
-        let g = AttributeValue::N(color.g.to_string());
-        let b = AttributeValue::N(color.b.to_string());
+fn key_schema(keys: &[(&str, &str, &str)]) -> Vec<KeySchemaElement> {
+    keys.iter()
+        .map(|(name, key_type, _)| {
+            KeySchemaElement::builder()
+                .attribute_name(*name)
+                .key_type(KeyType::from(*key_type))
+                .build()
+        })
+        .collect()
+}
+
+impl DynamoDB {
+    //Fails unless the table exists with the expected schema.
+    //With `create_table`, a missing table is created instead.
+    pub async fn new(dynamodb_config: &DynamoDBConfig) -> Result<Self> {
+        let ret = Self {
+            table_name: dynamodb_config.table_name.clone(),
+            client: new_client().await,
+        };
+        let table = match ret.describe_table().await? {
+            Some(table) => table,
+            None if dynamodb_config.create_table => ret.create_table().await?,
+            None => {
+                return Err(Error::config(
+                    format!("DynamoDB table `{}` doesn't exist", ret.table_name),
+                    "set `dynamodb.create_table` to create it",
+                ))
+            }
+        };
+        let issues = schema_issues(&table);
+        if !issues.is_empty() {
+            return Err(Error::config(
+                format!(
+                    "DynamoDB table `{}` has an unexpected schema",
+                    ret.table_name
+                ),
+                issues.join("; "),
+            ));
+        }
+        Ok(ret)
+    }
 
-        let request = self
+    //`None` if the table doesn't exist.
+    async fn describe_table(&self) -> Result<Option<TableDescription>> {
+        match self
             .client
-            .put_item()
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+        {
+            Ok(output) => Ok(output.table().cloned()),
+            Err(SdkError::ServiceError(context))
+                if context.err().is_resource_not_found_exception() =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(dynamodb_error("failed to describe the table", e)),
+        }
+    }
+
+    //Creates the table (on-demand capacity) and waits until it and its index become active.
+    async fn create_table(&self) -> Result<TableDescription> {
+        info!("creating DynamoDB table `{}`", self.table_name);
+        let attribute_definitions = TABLE_KEYS
+            .iter()
+            .chain(INDEX_KEYS)
+            .map(|(name, _, attribute_type)| {
+                AttributeDefinition::builder()
+                    .attribute_name(*name)
+                    .attribute_type(ScalarAttributeType::from(*attribute_type))
+                    .build()
+            })
+            .collect();
+        let index = GlobalSecondaryIndex::builder()
+            .index_name(INDEX_NAME)
+            .set_key_schema(Some(key_schema(INDEX_KEYS)))
+            .projection(
+                Projection::builder()
+                    .projection_type(ProjectionType::All)
+                    .build(),
+            )
+            .build();
+        self.client
+            .create_table()
             .table_name(&self.table_name)
-            .item("timestamp", timestamp)
-            .item("r", r)
-            .item("g", g)
-            .item("b", b);
+            .set_attribute_definitions(Some(attribute_definitions))
+            .set_key_schema(Some(key_schema(TABLE_KEYS)))
+            .global_secondary_indexes(index)
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await
+            .map_err(|e| dynamodb_error("failed to create the table", e))?;
+
+        let deadline = Instant::now() + TABLE_CREATION_TIMEOUT;
+        while Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            if let Some(table) = self.describe_table().await? {
+                let active = table.table_status() == Some(&TableStatus::Active)
+                    && table
+                        .global_secondary_indexes()
+                        .unwrap_or_default()
+                        .iter()
+                        .all(|index| index.index_status() == Some(&IndexStatus::Active));
+                if active {
+                    return Ok(table);
+                }
+            }
+        }
+        Err(Error::dynamodb(
+            "failed to create the table",
+            format!("not active after {:?}", TABLE_CREATION_TIMEOUT),
+        ))
+    }
 
-        request.send().await?;
+    async fn query(&self, query: Query) -> Result<Vec<Color>> {
+        let items = query
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .await
+            .map_err(|e| dynamodb_error("query failed", e))?;
+        items
+            .iter()
+            .map(|item| item_to_color(item).map_err(|e| Error::dynamodb("malformed item", e)))
+            .collect()
+    }
+}
+
+fn item_to_color(item: &HashMap<String, AttributeValue>) -> std::result::Result<Color, BoxError> {
+    let get = |name: &str| -> std::result::Result<u8, BoxError> {
+        match item.get(name) {
+            Some(AttributeValue::N(n)) => Ok(n.parse()?),
+            _ => Err(format!("attribute `{}` is missing or not a number", name).into()),
+        }
+    };
+    //`a` is put only for the colors which are not opaque.
+    let a = if item.contains_key("a") {
+        get("a")?
+    } else {
+        u8::MAX
+    };
+    Ok(Color::with_alpha(get("r")?, get("g")?, get("b")?, a))
+}
+
+#[async_trait]
+impl KeyValueLog for DynamoDB {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        let now = SystemTime::now();
+        let mut request = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("color", AttributeValue::S(color.to_hex()))
+            //Unique even for the items put in the same millisecond.
+            .item("id", AttributeValue::S(unique_id::generate()))
+            .item(
+                "day",
+                AttributeValue::S(day_of(OffsetDateTime::from(now).date())),
+            )
+            .item("created_at", AttributeValue::N(millis(now).to_string()))
+            .item("r", AttributeValue::N(color.r.to_string()))
+            .item("g", AttributeValue::N(color.g.to_string()))
+            .item("b", AttributeValue::N(color.b.to_string()));
+        if !color.is_opaque() {
+            request = request.item("a", AttributeValue::N(color.a.to_string()));
+        }
+        request
+            .send()
+            .await
+            .map_err(|e| dynamodb_error("insert failed", e))?;
         Ok(())
     }
 
-    #[cfg(test)]
-    pub async fn select_by_color(
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        self.query(
+            self.client
+                .query()
+                .table_name(&self.table_name)
+                .key_condition_expression("#color = :color")
+                .expression_attribute_names("#color", "color")
+                .expression_attribute_values(":color", AttributeValue::S(color.to_hex())),
+        )
+        .await
+    }
+
+    async fn select_by_time_range(&self, from: SystemTime, to: SystemTime) -> Result<Vec<Color>> {
+        let mut ret = vec![];
+        if from >= to {
+            return Ok(ret);
+        }
+        //Both ends inclusive.
+        let (from, to) = (from, to - Duration::from_millis(1));
+        let mut day = date_of(from)?;
+        let last_day = date_of(to)?;
+        while day <= last_day {
+            ret.extend(
+                self.query(
+                    self.client
+                        .query()
+                        .table_name(&self.table_name)
+                        .index_name(INDEX_NAME)
+                        .key_condition_expression(
+                            "#day = :day AND created_at BETWEEN :from AND :to",
+                        )
+                        .expression_attribute_names("#day", "day")
+                        .expression_attribute_values(":day", AttributeValue::S(day_of(day)))
+                        .expression_attribute_values(
+                            ":from",
+                            AttributeValue::N(millis(from).to_string()),
+                        )
+                        .expression_attribute_values(
+                            ":to",
+                            AttributeValue::N(millis(to).to_string()),
+                        ),
+                )
+                .await?,
+            );
+            match day.next_day() {
+                Some(next) => day = next,
+                None => break,
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/*-------------------------------------*/
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+//Each key is an item of the form `{key, fingerprint, expires_at[, status, body]}`,
+//where `status` and `body` are set once the request has completed.
+//`expires_at` is in epoch seconds, so it can also be used as the TTL attribute of the table.
+pub struct DynamoDBIdempotencyStore {
+    table_name: String,
+    client: aws_sdk_dynamodb::Client,
+}
+
+impl DynamoDBIdempotencyStore {
+    pub async fn new(table_name: &str) -> Self {
+        Self {
+            table_name: table_name.to_string(),
+            client: new_client().await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Reservation> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key("key", AttributeValue::S(key.to_string()))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| dynamodb_error("failed to get an idempotency key", e))?;
+        let Some(item) = output.item() else {
+            //Expired and removed just now.
+            return Ok(Reservation::InProgress);
+        };
+        let get_s = |name: &str| match item.get(name) {
+            Some(AttributeValue::S(s)) => Some(s.clone()),
+            _ => None,
+        };
+        let status = match item.get("status") {
+            Some(AttributeValue::N(n)) => n.parse().ok(),
+            _ => None,
+        };
+        Ok(match (get_s("fingerprint"), status, get_s("body")) {
+            (Some(fingerprint), Some(status), Some(body)) => Reservation::Done {
+                fingerprint,
+                response: StoredResponse { status, body },
+            },
+            _ => Reservation::InProgress,
+        })
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for DynamoDBIdempotencyStore {
+    async fn reserve(&self, key: &str, fingerprint: &str, window_sec: u64) -> Result<Reservation> {
+        let now = now_secs();
+        let res = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("key", AttributeValue::S(key.to_string()))
+            .item("fingerprint", AttributeValue::S(fingerprint.to_string()))
+            .item(
+                "expires_at",
+                AttributeValue::N(now.saturating_add(window_sec).to_string()),
+            )
+            //Expired items may remain until DynamoDB's TTL actually deletes them.
+            .condition_expression("attribute_not_exists(#key) OR expires_at < :now")
+            .expression_attribute_names("#key", "key")
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send()
+            .await;
+        match res {
+            Ok(_) => Ok(Reservation::New),
+            Err(SdkError::ServiceError(context))
+                if context.err().is_conditional_check_failed_exception() =>
+            {
+                self.get(key).await
+            }
+            Err(e) => Err(dynamodb_error("failed to reserve an idempotency key", e)),
+        }
+    }
+
+    async fn complete(
         &self,
-        color: &Color,
-    ) -> Result<Vec<HashMap<String, AttributeValue>>, Box<dyn Error>> {
+        key: &str,
+        fingerprint: &str,
+        response: &StoredResponse,
+        window_sec: u64,
+    ) -> Result<()> {
         self.client
-            .scan()
+            .put_item()
             .table_name(&self.table_name)
-            .filter_expression("r = :r and g = :g and b = :b")
-            .expression_attribute_values(":r", AttributeValue::N(color.r.to_string()))
-            .expression_attribute_values(":g", AttributeValue::N(color.g.to_string()))
-            .expression_attribute_values(":b", AttributeValue::N(color.b.to_string()))
-            .into_paginator()
-            .items()
+            .item("key", AttributeValue::S(key.to_string()))
+            .item("fingerprint", AttributeValue::S(fingerprint.to_string()))
+            .item(
+                "expires_at",
+                AttributeValue::N(now_secs().saturating_add(window_sec).to_string()),
+            )
+            .item("status", AttributeValue::N(response.status.to_string()))
+            .item("body", AttributeValue::S(response.body.clone()))
+            .send()
+            .await
+            .map_err(|e| dynamodb_error("failed to store an idempotency key", e))?;
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<()> {
+        self.client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key("key", AttributeValue::S(key.to_string()))
             .send()
-            .collect::<Result<Vec<_>, _>>()
             .await
-            .map_err(|e| e.into())
+            .map_err(|e| dynamodb_error("failed to release an idempotency key", e))?;
+        Ok(())
     }
 }
 
@@ -77,22 +525,33 @@ impl DynamoDB {
 #[cfg(test)]
 mod dynamodb_tests {
 
+    use aws_sdk_dynamodb::model::GlobalSecondaryIndexDescription;
+
     use super::super::config::Config;
     use super::*;
 
     #[tokio::test]
-    async fn test01() -> Result<(), Box<dyn Error>> {
-        let config = Config::new("./config.json");
+    async fn test01() -> Result<()> {
+        //Requires a live DynamoDB table and `./config.json`.
+        if std::env::var_os("LIVE_TESTS").is_none() {
+            return Ok(());
+        }
+        let config = Config::new("./config.json")?;
         let dynamodb = DynamoDB::new(&config.dynamodb).await?;
         let color = Color::new(100, 50, 20);
 
         let num_entry = dynamodb.select_by_color(&color).await?.len();
 
+        let start = SystemTime::now();
         let res = dynamodb.insert(&color).await;
         println!("{:?}", res);
         assert!(res.is_ok());
 
         assert_eq!(num_entry + 1, dynamodb.select_by_color(&color).await?.len());
+        assert!(dynamodb
+            .select_by_time_range(start, SystemTime::now() + Duration::from_secs(1))
+            .await?
+            .contains(&color));
 
         let res = dynamodb.select_by_color(&color).await;
         println!("{:?}", res);
@@ -100,6 +559,75 @@ mod dynamodb_tests {
 
         Ok(())
     }
+
+    fn table(
+        keys: &[(&str, &str, &str)],
+        index_keys: Option<&[(&str, &str, &str)]>,
+    ) -> TableDescription {
+        let mut builder = TableDescription::builder().set_key_schema(Some(key_schema(keys)));
+        for (name, _, attribute_type) in keys.iter().chain(index_keys.unwrap_or_default()) {
+            builder = builder.attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name(*name)
+                    .attribute_type(ScalarAttributeType::from(*attribute_type))
+                    .build(),
+            );
+        }
+        if let Some(index_keys) = index_keys {
+            builder = builder.global_secondary_indexes(
+                GlobalSecondaryIndexDescription::builder()
+                    .index_name(INDEX_NAME)
+                    .set_key_schema(Some(key_schema(index_keys)))
+                    .projection(
+                        Projection::builder()
+                            .projection_type(ProjectionType::All)
+                            .build(),
+                    )
+                    .build(),
+            );
+        }
+        builder.build()
+    }
+
+    #[test]
+    fn test02() {
+        assert!(schema_issues(&table(TABLE_KEYS, Some(INDEX_KEYS))).is_empty());
+
+        //The schema before the index was introduced.
+        assert_eq!(
+            vec![
+                "the keys of the table must be color (HASH, S), id (RANGE, S) but are timestamp (HASH, S)"
+                    .to_string(),
+                "the global secondary index `by_time` is missing".to_string(),
+            ],
+            schema_issues(&table(&[("timestamp", "HASH", "S")], None))
+        );
+
+        assert_eq!(
+            vec![
+                "the keys of the index `by_time` must be day (HASH, S), created_at (RANGE, N) but are day (HASH, S), created_at (RANGE, S)"
+                    .to_string()
+            ],
+            schema_issues(&table(
+                TABLE_KEYS,
+                Some(&[("day", "HASH", "S"), ("created_at", "RANGE", "S")])
+            ))
+        );
+    }
+
+    #[test]
+    fn test03() {
+        let at = |ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms);
+        assert_eq!("1970-01-01", day_of(date_of(at(0)).unwrap()));
+        assert_eq!(
+            "9999-12-31",
+            day_of(date_of(at(253_402_300_799_999)).unwrap())
+        );
+        assert_eq!(
+            "invalid_request",
+            date_of(at(300_000_000_000_000)).unwrap_err().code()
+        );
+    }
 }
 
 /*-------------------------------------*/
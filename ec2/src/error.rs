@@ -0,0 +1,271 @@
+use std::fmt;
+
+use warp::http::StatusCode;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    //The request body is malformed or out of range.
+    InvalidRequest {
+        message: String,
+        source: Option<BoxError>,
+    },
+    Config {
+        message: String,
+        source: Option<BoxError>,
+    },
+    Image(image::ImageError),
+    Storage {
+        message: String,
+        source: Option<BoxError>,
+        //Whether the failure is transient (e.g. throttling) and the operation is worth retrying.
+        retryable: bool,
+    },
+    Rds {
+        message: String,
+        source: Option<BoxError>,
+        retryable: bool,
+    },
+    DynamoDB {
+        message: String,
+        source: Option<BoxError>,
+        retryable: bool,
+    },
+    Outbox {
+        message: String,
+        source: Option<BoxError>,
+    },
+    //The backend is skipped as its circuit breaker is open (see `breaker`).
+    CircuitOpen {
+        backend: String,
+    },
+    //Another request with the same `Idempotency-Key` is still being processed.
+    IdempotencyKeyInUse,
+    //The `Idempotency-Key` has been used for a request with another body.
+    IdempotencyKeyReused,
+    //No route or resource matches the request.
+    NotFound {
+        message: String,
+    },
+    //The path exists but doesn't accept the method.
+    MethodNotAllowed,
+    //The admin token is missing or wrong.
+    Unauthorized,
+    //`Content-Type` of the request is not `application/json`.
+    UnsupportedMediaType {
+        content_type: String,
+    },
+}
+
+impl Error {
+    pub fn invalid_request(message: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::InvalidRequest {
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn config(message: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::Config {
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn storage(message: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::Storage {
+            message: message.into(),
+            source: Some(source.into()),
+            retryable: false,
+        }
+    }
+
+    pub fn rds(message: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::Rds {
+            message: message.into(),
+            source: Some(source.into()),
+            retryable: false,
+        }
+    }
+
+    pub fn dynamodb(message: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::DynamoDB {
+            message: message.into(),
+            source: Some(source.into()),
+            retryable: false,
+        }
+    }
+
+    pub fn outbox(message: impl Into<String>, source: impl Into<BoxError>) -> Self {
+        Self::Outbox {
+            message: message.into(),
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::NotFound {
+            message: message.into(),
+        }
+    }
+
+    pub fn circuit_open(backend: impl Into<String>) -> Self {
+        Self::CircuitOpen {
+            backend: backend.into(),
+        }
+    }
+
+    //Marks a backend error as transient or not. Other errors are never retryable.
+    pub fn retryable(mut self, yes: bool) -> Self {
+        if let Self::Storage { retryable, .. }
+        | Self::Rds { retryable, .. }
+        | Self::DynamoDB { retryable, .. } = &mut self
+        {
+            *retryable = yes;
+        }
+        self
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Storage { retryable, .. }
+            | Self::Rds { retryable, .. }
+            | Self::DynamoDB { retryable, .. } => *retryable,
+            _ => false,
+        }
+    }
+
+    //Machine-readable identifier returned to clients as `code` in `Response`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidRequest { .. } => "invalid_request",
+            Self::Config { .. } => "config_error",
+            Self::Image(_) => "image_encoding_failed",
+            Self::Storage { .. } => "storage_failed",
+            Self::Rds { .. } => "rds_failed",
+            Self::DynamoDB { .. } => "dynamodb_failed",
+            Self::Outbox { .. } => "outbox_failed",
+            Self::CircuitOpen { .. } => "circuit_open",
+            Self::IdempotencyKeyInUse => "idempotency_key_in_use",
+            Self::IdempotencyKeyReused => "idempotency_key_reused",
+            Self::NotFound { .. } => "not_found",
+            Self::MethodNotAllowed => "method_not_allowed",
+            Self::Unauthorized => "unauthorized",
+            Self::UnsupportedMediaType { .. } => "unsupported_media_type",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+            //Requests which can't be encoded are rejected as `InvalidRequest` beforehand, so this is a bug.
+            Self::Config { .. } | Self::Outbox { .. } | Self::Image(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Self::IdempotencyKeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::IdempotencyKeyInUse => StatusCode::CONFLICT,
+            Self::NotFound { .. } => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::UnsupportedMediaType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Storage { .. } => StatusCode::BAD_GATEWAY,
+            Self::Rds { .. } | Self::DynamoDB { .. } | Self::CircuitOpen { .. } => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidRequest { message, .. } => write!(f, "invalid request: {}", message),
+            Self::Config { message, .. } => write!(f, "config error: {}", message),
+            Self::Image(e) => write!(f, "failed to encode the image: {}", e),
+            Self::Storage { message, .. } => write!(f, "storage error: {}", message),
+            Self::Rds { message, .. } => write!(f, "RDS error: {}", message),
+            Self::DynamoDB { message, .. } => write!(f, "DynamoDB error: {}", message),
+            Self::Outbox { message, .. } => write!(f, "outbox error: {}", message),
+            Self::CircuitOpen { backend } => {
+                write!(
+                    f,
+                    "{} is unavailable as its circuit breaker is open",
+                    backend
+                )
+            }
+            Self::IdempotencyKeyInUse => {
+                write!(f, "a request with the same idempotency key is in progress")
+            }
+            Self::IdempotencyKeyReused => {
+                write!(f, "the idempotency key has been used for another request")
+            }
+            Self::NotFound { message } => write!(f, "not found: {}", message),
+            Self::MethodNotAllowed => write!(f, "method not allowed"),
+            Self::Unauthorized => write!(f, "unauthorized"),
+            Self::UnsupportedMediaType { content_type } => write!(
+                f,
+                "unsupported media type `{}`: expected `application/json`",
+                content_type
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Image(e) => Some(e),
+            Self::CircuitOpen { .. }
+            | Self::IdempotencyKeyInUse
+            | Self::IdempotencyKeyReused
+            | Self::NotFound { .. }
+            | Self::MethodNotAllowed
+            | Self::Unauthorized
+            | Self::UnsupportedMediaType { .. } => None,
+            Self::InvalidRequest { source, .. }
+            | Self::Config { source, .. }
+            | Self::Storage { source, .. }
+            | Self::Rds { source, .. }
+            | Self::DynamoDB { source, .. }
+            | Self::Outbox { source, .. } => source
+                .as_ref()
+                .map(|e| e.as_ref() as &(dyn std::error::Error + 'static)),
+        }
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+
+    #[test]
+    fn test01() {
+        let e = Error::rds("insert failed", "connection refused");
+        assert_eq!("RDS error: insert failed", e.to_string());
+        assert_eq!("connection refused", e.source().unwrap().to_string());
+        assert_eq!("rds_failed", e.code());
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, e.status());
+
+        assert!(!e.is_retryable());
+        assert!(e.retryable(true).is_retryable());
+        assert!(!Error::config("a", "b").retryable(true).is_retryable());
+
+        let e = Error::storage("upload failed", Error::rds("a", "b"));
+        assert_eq!("b", e.source().unwrap().source().unwrap().to_string());
+    }
+}
+
+/*-------------------------------------*/
@@ -1,27 +1,377 @@
+use std::io::Cursor;
+
 use bytes::Bytes;
-use image::{codecs::png::PngEncoder, ColorType, ImageEncoder, Rgb, RgbImage};
+use image::{
+    codecs::{
+        bmp::BmpEncoder,
+        gif::GifEncoder,
+        ico::IcoEncoder,
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+        tiff::TiffEncoder,
+    },
+    error::{EncodingError, ImageFormatHint},
+    ColorType, ImageEncoder, ImageError, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use serde::Deserialize;
 
 use super::color::Color;
+use super::error::{Error, Result};
+
+/*-------------------------------------*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FormatName {
+    #[default]
+    Png,
+    Jpeg,
+    Webp,
+    Gif,
+    Bmp,
+    Tiff,
+    Ico,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    Fast,
+    #[default]
+    Default,
+    Best,
+}
+
+//Used when `quality` is omitted for JPEG.
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+//An output format together with its options.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png { compression: PngCompression },
+    //1 to 100.
+    Jpeg { quality: u8 },
+    //Lossless.
+    Webp,
+    Gif,
+    Bmp,
+    Tiff,
+    //Up to 256x256.
+    Ico,
+}
+
+impl Format {
+    //Builds a format from the fields of a request, where each option is allowed only for its format.
+    pub fn new(
+        name: Option<FormatName>,
+        quality: Option<u8>,
+        compression: Option<PngCompression>,
+    ) -> Result<Self> {
+        let name = name.unwrap_or_default();
+        if quality.is_some() && name != FormatName::Jpeg {
+            return Err(Error::invalid_request(
+                "invalid format options",
+                "`quality` is only for `jpeg`",
+            ));
+        }
+        if compression.is_some() && name != FormatName::Png {
+            return Err(Error::invalid_request(
+                "invalid format options",
+                "`compression` is only for `png`",
+            ));
+        }
+        Ok(match name {
+            FormatName::Png => Self::Png {
+                compression: compression.unwrap_or_default(),
+            },
+            FormatName::Jpeg => {
+                let quality = quality.unwrap_or(DEFAULT_JPEG_QUALITY);
+                if !(1..=100).contains(&quality) {
+                    return Err(Error::invalid_request(
+                        "invalid format options",
+                        "`quality` must be between 1 and 100",
+                    ));
+                }
+                Self::Jpeg { quality }
+            }
+            FormatName::Webp => Self::Webp,
+            FormatName::Gif => Self::Gif,
+            FormatName::Bmp => Self::Bmp,
+            FormatName::Tiff => Self::Tiff,
+            FormatName::Ico => Self::Ico,
+        })
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png { .. } => "png",
+            Self::Jpeg { .. } => "jpg",
+            Self::Webp => "webp",
+            Self::Gif => "gif",
+            Self::Bmp => "bmp",
+            Self::Tiff => "tiff",
+            Self::Ico => "ico",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Png { .. } => "image/png",
+            Self::Jpeg { .. } => "image/jpeg",
+            Self::Webp => "image/webp",
+            Self::Gif => "image/gif",
+            Self::Bmp => "image/bmp",
+            Self::Tiff => "image/tiff",
+            Self::Ico => "image/vnd.microsoft.icon",
+        }
+    }
+
+    //Whether the format can encode a color which is not opaque.
+    //GIF can, but only fully transparent pixels.
+    pub fn check_color(&self, color: &Color) -> Result<()> {
+        match self {
+            Self::Jpeg { .. } if !color.is_opaque() => Err(Error::invalid_request(
+                "invalid color for the format",
+                "`jpeg` doesn't support transparency",
+            )),
+            Self::Gif if !matches!(color.a, 0 | u8::MAX) => Err(Error::invalid_request(
+                "invalid color for the format",
+                "`gif` supports only fully transparent or opaque colors",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    //Whether the format can encode an image of the size.
+    pub fn check_size(&self, width: u32, height: u32) -> Result<()> {
+        match self {
+            Self::Ico if width > 256 || height > 256 => Err(Error::invalid_request(
+                "invalid size for the format",
+                "`ico` supports up to 256x256",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    //Distinguishes the options which change the encoded bytes, e.g. `-q90` for JPEG quality 90.
+    //Empty for the defaults of PNG, so that such keys are the same as before formats were introduced.
+    pub fn options_suffix(&self) -> String {
+        match self {
+            Self::Png {
+                compression: PngCompression::Fast,
+            } => "-fast".to_string(),
+            Self::Png {
+                compression: PngCompression::Best,
+            } => "-best".to_string(),
+            Self::Jpeg { quality } => format!("-q{}", quality),
+            _ => String::new(),
+        }
+    }
+}
+
+/*-------------------------------------*/
 
 pub struct Image;
 
 impl Image {
-    pub fn create_image(width: u32, height: u32, color: &Color) -> Bytes {
-        let mut img = RgbImage::new(width, height);
-        for x in 0..width {
-            for y in 0..height {
-                let r = color.r;
-                let g = color.g;
-                let b = color.b;
-                img.put_pixel(x, y, Rgb([r, g, b]));
+    //The image is encoded as RGBA only when it is not opaque (or the format requires it).
+    pub fn create_image(width: u32, height: u32, color: &Color, format: &Format) -> Result<Bytes> {
+        format.check_color(color)?;
+        format.check_size(width, height)?;
+        //Icons embed PNG, which must be RGBA.
+        let (img, color_type) = if color.is_opaque() && *format != Format::Ico {
+            let pixel = Rgb([color.r, color.g, color.b]);
+            (
+                RgbImage::from_pixel(width, height, pixel).into_raw(),
+                ColorType::Rgb8,
+            )
+        } else {
+            let pixel = Rgba([color.r, color.g, color.b, color.a]);
+            (
+                RgbaImage::from_pixel(width, height, pixel).into_raw(),
+                ColorType::Rgba8,
+            )
+        };
+
+        let mut buf = Vec::new();
+        match *format {
+            Format::Png { compression } => {
+                let compression = match compression {
+                    PngCompression::Fast => CompressionType::Fast,
+                    PngCompression::Default => CompressionType::Default,
+                    PngCompression::Best => CompressionType::Best,
+                };
+                PngEncoder::new_with_quality(&mut buf, compression, FilterType::Adaptive)
+                    .write_image(&img, width, height, color_type)?;
+            }
+            Format::Jpeg { quality } => JpegEncoder::new_with_quality(&mut buf, quality)
+                .write_image(&img, width, height, color_type)?,
+            //`image` encodes WebP only through libwebp, so the pure-Rust lossless encoder is used instead.
+            Format::Webp => image_webp::WebPEncoder::new(&mut buf)
+                .encode(
+                    &img,
+                    width,
+                    height,
+                    match color_type {
+                        ColorType::Rgb8 => image_webp::ColorType::Rgb8,
+                        _ => image_webp::ColorType::Rgba8,
+                    },
+                )
+                .map_err(|e| {
+                    ImageError::Encoding(EncodingError::new(
+                        ImageFormatHint::Exact(ImageFormat::WebP),
+                        e,
+                    ))
+                })?,
+            Format::Gif => GifEncoder::new(&mut buf).encode(&img, width, height, color_type)?,
+            Format::Bmp => {
+                BmpEncoder::new(&mut buf).write_image(&img, width, height, color_type)?
+            }
+            Format::Tiff => TiffEncoder::new(Cursor::new(&mut buf))
+                .write_image(&img, width, height, color_type)?,
+            Format::Ico => {
+                IcoEncoder::new(&mut buf).write_image(&img, width, height, color_type)?
             }
         }
+        Ok(buf.into())
+    }
+}
 
-        let mut buf = Vec::new();
-        let encoder = PngEncoder::new(&mut buf);
-        encoder
-            .write_image(&img, width, height, ColorType::Rgb8)
-            .unwrap();
-        buf.into()
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test01() -> Result<()> {
+        let color = Color::new(255, 128, 0);
+        for (format, image_format) in [
+            (Format::new(None, None, None)?, ImageFormat::Png),
+            (
+                Format::new(Some(FormatName::Png), None, Some(PngCompression::Best))?,
+                ImageFormat::Png,
+            ),
+            (
+                Format::new(Some(FormatName::Jpeg), Some(90), None)?,
+                ImageFormat::Jpeg,
+            ),
+            (
+                Format::new(Some(FormatName::Webp), None, None)?,
+                ImageFormat::WebP,
+            ),
+            (
+                Format::new(Some(FormatName::Gif), None, None)?,
+                ImageFormat::Gif,
+            ),
+            (
+                Format::new(Some(FormatName::Bmp), None, None)?,
+                ImageFormat::Bmp,
+            ),
+            (
+                Format::new(Some(FormatName::Tiff), None, None)?,
+                ImageFormat::Tiff,
+            ),
+            (
+                Format::new(Some(FormatName::Ico), None, None)?,
+                ImageFormat::Ico,
+            ),
+        ] {
+            let image = Image::create_image(16, 8, &color, &format)?;
+            assert_eq!(
+                image_format,
+                image::guess_format(&image).map_err(Error::from)?,
+                "{:?}",
+                format
+            );
+            let decoded = image::load_from_memory(&image)
+                .map_err(Error::from)?
+                .to_rgb8();
+            assert_eq!((16, 8), decoded.dimensions());
+            //JPEG is lossy.
+            let pixel = decoded.get_pixel(15, 7).0;
+            assert!(
+                pixel
+                    .iter()
+                    .zip([255, 128, 0])
+                    .all(|(a, b)| a.abs_diff(b) <= 2),
+                "{:?}: {:?}",
+                format,
+                pixel
+            );
+        }
+
+        let ico = Format::new(Some(FormatName::Ico), None, None)?;
+        assert!(Image::create_image(256, 256, &color, &ico).is_ok());
+        assert_eq!(
+            "invalid_request",
+            Image::create_image(300, 200, &color, &ico)
+                .unwrap_err()
+                .code()
+        );
+        assert!(ico.check_size(256, 257).is_err());
+        assert!(Format::Webp.check_size(300, 200).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test02() {
+        assert_eq!(
+            "invalid_request",
+            Format::new(Some(FormatName::Png), Some(90), None)
+                .unwrap_err()
+                .code()
+        );
+        assert!(Format::new(Some(FormatName::Jpeg), None, Some(PngCompression::Fast)).is_err());
+        assert!(Format::new(Some(FormatName::Jpeg), Some(0), None).is_err());
+        assert!(Format::new(Some(FormatName::Jpeg), Some(101), None).is_err());
+        assert_eq!(
+            Format::Jpeg { quality: 75 },
+            Format::new(Some(FormatName::Jpeg), None, None).unwrap()
+        );
+
+        assert_eq!("", Format::new(None, None, None).unwrap().options_suffix());
+        assert_eq!(
+            "-q90",
+            Format::new(Some(FormatName::Jpeg), Some(90), None)
+                .unwrap()
+                .options_suffix()
+        );
+    }
+
+    #[test]
+    fn test03() -> Result<()> {
+        let translucent = Color::with_alpha(255, 128, 0, 128);
+        let transparent = Color::with_alpha(255, 128, 0, 0);
+        for (name, color) in [
+            (FormatName::Png, &translucent),
+            (FormatName::Webp, &translucent),
+            (FormatName::Gif, &transparent),
+            (FormatName::Bmp, &translucent),
+            (FormatName::Tiff, &translucent),
+            (FormatName::Ico, &translucent),
+        ] {
+            let format = Format::new(Some(name), None, None)?;
+            let image = Image::create_image(16, 8, color, &format)?;
+            let decoded = image::load_from_memory(&image)
+                .map_err(Error::from)?
+                .to_rgba8();
+            assert_eq!(color.a, decoded.get_pixel(15, 7).0[3], "{:?}", format);
+        }
+
+        let jpeg = Format::new(Some(FormatName::Jpeg), None, None)?;
+        assert!(Image::create_image(16, 8, &Color::new(255, 128, 0), &jpeg).is_ok());
+        assert_eq!(
+            "invalid_request",
+            Image::create_image(16, 8, &translucent, &jpeg)
+                .unwrap_err()
+                .code()
+        );
+        assert!(Format::Gif.check_color(&translucent).is_err());
+
+        Ok(())
     }
 }
+
+/*-------------------------------------*/
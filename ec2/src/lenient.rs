@@ -0,0 +1,292 @@
+//A deserializer of `serde_json::Value` which lets strings stand for numbers, booleans, objects and arrays.
+//The values of environment variables are all strings, and the type they should have is known only to the target type.
+//So each value is converted when it is deserialized, depending on what the target field asks for.
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+use serde::Deserializer;
+use serde_json::{Error, Map, Value};
+
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(Lenient(value))
+}
+
+struct Lenient(Value);
+
+impl Lenient {
+    //Parses a string which should be a JSON value of another type, such as `8080` or `{"max_attempts": 5}`.
+    fn parse(s: &str, expected: &str) -> Result<Value, Error> {
+        serde_json::from_str(s.trim())
+            .map_err(|_| de::Error::custom(format!("expected {} but got `{}`", expected, s)))
+    }
+}
+
+macro_rules! deserialize_number {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                match self.0 {
+                    Value::String(s) => match Lenient::parse(&s, "a number")? {
+                        Value::Number(n) => Value::Number(n).$method(visitor),
+                        _ => Err(de::Error::custom(format!("expected a number but got `{}`", s))),
+                    },
+                    v => v.$method(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Lenient {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Array(a) => visitor.visit_seq(LenientSeq(a.into_iter())),
+            Value::Object(m) => visitor.visit_map(LenientMap::new(m)),
+            v => v.deserialize_any(visitor),
+        }
+    }
+
+    deserialize_number! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_f32 deserialize_f64
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::String(s) => match s.as_str() {
+                "true" => visitor.visit_bool(true),
+                "false" => visitor.visit_bool(false),
+                _ => Err(de::Error::custom(format!(
+                    "expected `true` or `false` but got `{}`",
+                    s
+                ))),
+            },
+            v => v.deserialize_bool(visitor),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Number(n) => visitor.visit_string(n.to_string()),
+            Value::Bool(b) => visitor.visit_string(b.to_string()),
+            v => v.deserialize_string(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            v => visitor.visit_some(Lenient(v)),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Array(a) => visitor.visit_seq(LenientSeq(a.into_iter())),
+            Value::String(s) => match Lenient::parse(&s, "an array")? {
+                Value::Array(a) => visitor.visit_seq(LenientSeq(a.into_iter())),
+                _ => Err(de::Error::custom(format!(
+                    "expected an array but got `{}`",
+                    s
+                ))),
+            },
+            v => v.deserialize_seq(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            Value::Object(m) => visitor.visit_map(LenientMap::new(m)),
+            Value::String(s) => match Lenient::parse(&s, "an object")? {
+                Value::Object(m) => visitor.visit_map(LenientMap::new(m)),
+                _ => Err(de::Error::custom(format!(
+                    "expected an object but got `{}`",
+                    s
+                ))),
+            },
+            v => v.deserialize_map(visitor),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.0.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        char bytes byte_buf unit unit_struct tuple_struct identifier ignored_any
+    }
+}
+
+struct LenientSeq(std::vec::IntoIter<Value>);
+
+impl<'de> SeqAccess<'de> for LenientSeq {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.0
+            .next()
+            .map(|v| seed.deserialize(Lenient(v)))
+            .transpose()
+    }
+}
+
+struct LenientMap {
+    entries: serde_json::map::IntoIter,
+    value: Option<Value>,
+}
+
+impl LenientMap {
+    fn new(map: Map<String, Value>) -> Self {
+        Self {
+            entries: map.into_iter(),
+            value: None,
+        }
+    }
+}
+
+impl<'de> MapAccess<'de> for LenientMap {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        match self.entries.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self
+            .value
+            .take()
+            .expect("`next_value` is called before `next_key`");
+        seed.deserialize(Lenient(value))
+    }
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Inner {
+        n: u32,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Outer {
+        port: u16,
+        ratio: f64,
+        flag: bool,
+        password: String,
+        name: Option<String>,
+        inner: Inner,
+        list: Vec<u8>,
+    }
+
+    #[test]
+    fn test01() {
+        let json = serde_json::json!({
+            "port": "8080",
+            "ratio": "0.5",
+            "flag": "true",
+            "password": 12345,
+            "name": "null",
+            "inner": {"n": "3"},
+            "list": "[1, 2]",
+        });
+        assert_eq!(
+            Outer {
+                port: 8080,
+                ratio: 0.5,
+                flag: true,
+                password: "12345".to_string(),
+                name: Some("null".to_string()),
+                inner: Inner { n: 3 },
+                list: vec![1, 2],
+            },
+            from_value::<Outer>(json).unwrap()
+        );
+
+        let json = serde_json::json!({
+            "port": 8080,
+            "ratio": 0.5,
+            "flag": false,
+            "password": "abc",
+            "name": null,
+            "inner": "{\"n\": 3}",
+            "list": [1],
+        });
+        assert_eq!(None, from_value::<Outer>(json).unwrap().name);
+
+        for (field, value) in [
+            ("port", "80a"),
+            ("port", "70000"),
+            ("flag", "yes"),
+            ("inner", "3"),
+        ] {
+            let mut json = serde_json::json!({
+                "port": 8080,
+                "ratio": 0.5,
+                "flag": false,
+                "password": "abc",
+                "inner": {"n": 3},
+                "list": [],
+            });
+            json[field] = Value::String(value.to_string());
+            assert!(from_value::<Outer>(json).is_err(), "{}: {}", field, value);
+        }
+    }
+}
+
+/*-------------------------------------*/
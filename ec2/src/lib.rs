@@ -1,41 +1,80 @@
+pub mod backend;
+pub mod blocking;
+pub mod breaker;
 pub mod color;
 pub mod config;
 pub mod dynamodb;
+pub mod error;
 pub mod image;
+pub mod lenient;
+pub mod local;
+pub mod memory;
 pub mod mysql;
+pub mod outbox;
+pub mod reload;
+pub mod retry;
 pub mod s3;
+pub mod state;
 
-use std::{error::Error, sync::Arc, time::SystemTime};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use log::info;
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
 use warp::{
     self,
     filters::{body, header},
     http::{self, StatusCode},
-    Filter,
+    reject, Filter, Rejection, Reply,
 };
 
+use crate::backend::{IdempotencyStore, Reservation, StoredResponse};
 use crate::color::Color;
-use crate::config::Config;
-use crate::dynamodb::DynamoDB;
-use crate::image::Image;
-use crate::mysql::MySQL;
-use crate::s3::S3;
+use crate::config::{Backend, Config, Secret};
+use crate::error::{Error, Result};
+use crate::image::{Format, FormatName, Image, PngCompression};
+use crate::outbox::Sink;
+use crate::reload::Reloader;
+use crate::state::{
+    AwsBackendBuilder, BackendBuilder, LocalBackendBuilder, MemoryBackendBuilder, State,
+};
 
 /*-------------------------------------*/
 
 #[derive(Debug, Deserialize)]
 struct Request {
-    r: u8,
-    g: u8,
-    b: u8,
+    //Either `color` or `r`, `g` and `b` (see `request_color()`).
+    color: Option<String>,
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+    a: Option<u8>,
+    //`img_width` and `img_height` of the config by default, limited by `max_img_dimension` and `max_img_pixels`.
+    width: Option<u32>,
+    height: Option<u32>,
+    //PNG by default. The options are validated by `Format::new()`.
+    format: Option<FormatName>,
+    quality: Option<u8>,
+    compression: Option<PngCompression>,
 }
 
 impl Request {
-    fn new(json_string: &str) -> Result<Self, serde_json::Error> {
+    fn new(json_string: &str) -> Result<Self> {
         serde_json::from_str(json_string)
+            .map_err(|e| Error::invalid_request("failed to parse json", e))
+    }
+
+    //Validates the request and resolves the color, the size and the format of the image.
+    fn image_spec(&self, config: &Config) -> Result<(Color, (u32, u32), Format)> {
+        let color = request_color(self.color.as_deref(), self.r, self.g, self.b, self.a)?;
+        let size = image_size(config, self.width, self.height)?;
+        let format = Format::new(self.format, self.quality, self.compression)?;
+        format.check_color(&color)?;
+        format.check_size(size.0, size.1)?;
+        Ok((color, size, format))
     }
 }
 
@@ -45,11 +84,44 @@ impl Request {
 struct Response {
     status: String,
     url: Option<String>,
+    //Machine-readable reason of an error (see `Error::code()`). Omitted on success.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    //What was wrong with the request, e.g. which limit it exceeded. Omitted unless it is a client error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    //Backends which failed to be written to. Omitted when there is none.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failures: Vec<Failure>,
+}
+
+#[derive(Serialize)]
+struct Failure {
+    //`s3`, `rds` or `dynamodb`.
+    backend: String,
+    code: String,
 }
 
 impl Response {
     fn new(status: String, url: Option<String>) -> Self {
-        Self { status, url }
+        Self {
+            status,
+            url,
+            code: None,
+            message: None,
+            failures: vec![],
+        }
+    }
+
+    fn from_error(e: &Error) -> Self {
+        Self {
+            status: "error".to_string(),
+            url: None,
+            code: Some(e.code().to_string()),
+            //Server errors may expose the internals, so they are only logged.
+            message: e.status().is_client_error().then(|| error_chain(e)),
+            failures: vec![],
+        }
     }
 
     fn to_json_pretty(&self) -> String {
@@ -59,104 +131,783 @@ impl Response {
 
 /*-------------------------------------*/
 
-fn create_filename() -> String {
+//Names an image by what it is rendered from (e.g. `ff8000-300x200.png` or `ff8000-300x200-q90.jpg`),
+//so that the requests for the same image share one object.
+fn object_key(color: &Color, width: u32, height: u32, format: &Format) -> String {
     format!(
-        "{}.png",
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
+        "{}-{}x{}{}.{}",
+        color.to_hex(),
+        width,
+        height,
+        format.options_suffix(),
+        format.extension()
     )
 }
 
+//Whether `id` is a key `object_key()` returns, so that nothing else in the bucket can be looked up.
+//The key is rebuilt from what it is parsed into, which rejects non-canonical forms such as upper-case hex.
+fn is_object_key(id: &str) -> bool {
+    let rebuild = || -> Option<String> {
+        let (stem, extension) = id.rsplit_once('.')?;
+        let mut parts = stem.split('-');
+        let color = format!("#{}", parts.next()?).parse::<Color>().ok()?;
+        let (width, height) = parts.next()?.split_once('x')?;
+        let option = parts.next();
+        if parts.next().is_some() {
+            return None;
+        }
+        let (name, quality, compression) = match (extension, option) {
+            ("png", None) => (FormatName::Png, None, None),
+            ("png", Some("fast")) => (FormatName::Png, None, Some(PngCompression::Fast)),
+            ("png", Some("best")) => (FormatName::Png, None, Some(PngCompression::Best)),
+            ("jpg", Some(quality)) => (
+                FormatName::Jpeg,
+                Some(quality.strip_prefix('q')?.parse().ok()?),
+                None,
+            ),
+            ("webp", None) => (FormatName::Webp, None, None),
+            ("gif", None) => (FormatName::Gif, None, None),
+            ("bmp", None) => (FormatName::Bmp, None, None),
+            ("tiff", None) => (FormatName::Tiff, None, None),
+            ("ico", None) => (FormatName::Ico, None, None),
+            _ => return None,
+        };
+        let format = Format::new(Some(name), quality, compression).ok()?;
+        Some(object_key(
+            &color,
+            width.parse().ok()?,
+            height.parse().ok()?,
+            &format,
+        ))
+    };
+    rebuild().as_deref() == Some(id)
+}
+
+//Resolves the color of a request, given either as text in `color` (e.g. `#ff8000`, `orange` or `hsl(30, 100%, 50%)`;
+//see `Color::from_str()`) or as the components `r`, `g`, `b` and optionally `a` from 0 to 255 (opaque by default).
+fn request_color(
+    color: Option<&str>,
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+    a: Option<u8>,
+) -> Result<Color> {
+    match (color, r, g, b) {
+        (Some(color), None, None, None) if a.is_none() => color.parse(),
+        (None, Some(r), Some(g), Some(b)) => Ok(Color::with_alpha(r, g, b, a.unwrap_or(u8::MAX))),
+        (Some(_), ..) => Err(Error::invalid_request(
+            "invalid color",
+            "`color` can't be used together with `r`, `g`, `b` or `a`",
+        )),
+        _ => Err(Error::invalid_request(
+            "invalid color",
+            "either `color` or all of `r`, `g` and `b` are required",
+        )),
+    }
+}
+
+//Resolves the size of an image, which defaults to that of the config.
+//The limits are checked before anything is allocated, as the image is held in memory while it is encoded.
+fn image_size(config: &Config, width: Option<u32>, height: Option<u32>) -> Result<(u32, u32)> {
+    let width = width.unwrap_or(config.img_width);
+    let height = height.unwrap_or(config.img_height);
+    for (name, n) in [("width", width), ("height", height)] {
+        if n == 0 {
+            return Err(Error::invalid_request(
+                "invalid size",
+                format!("{} must be positive", name),
+            ));
+        }
+        if n > config.max_img_dimension {
+            return Err(Error::invalid_request(
+                "invalid size",
+                format!(
+                    "{} {} exceeds the limit of {} (`max_img_dimension`)",
+                    name, n, config.max_img_dimension
+                ),
+            ));
+        }
+    }
+    let pixels = u64::from(width) * u64::from(height);
+    if pixels > config.max_img_pixels {
+        return Err(Error::invalid_request(
+            "invalid size",
+            format!(
+                "{}x{} ({} pixels) exceeds the limit of {} pixels (`max_img_pixels`)",
+                width, height, pixels, config.max_img_pixels
+            ),
+        ));
+    }
+    Ok((width, height))
+}
+
+//Formats an error together with all of its sources, e.g. `storage error: upload failed: <cause>`.
+fn error_chain(e: &Error) -> String {
+    let mut ret = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(s) = source {
+        ret.push_str(&format!(": {}", s));
+        source = s.source();
+    }
+    ret
+}
+
+fn error_response(e: &Error) -> http::Result<http::Response<String>> {
+    http::Response::builder()
+        .status(e.status())
+        .header("Content-Type", "application/json")
+        .body(Response::from_error(e).to_json_pretty())
+}
+
+//The URL of the uploaded image, and the results of the writes to the logs, which are independent of each other.
+struct Outcome {
+    url: String,
+    rds: Result<()>,
+    dynamodb: Result<()>,
+}
+
+impl Outcome {
+    fn failures(&self) -> Vec<(&'static str, &Error)> {
+        [
+            ("rds", self.rds.as_ref().err()),
+            ("dynamodb", self.dynamodb.as_ref().err()),
+        ]
+        .into_iter()
+        .filter_map(|(backend, e)| e.map(|e| (backend, e)))
+        .collect()
+    }
+
+    //The image is what the client asked for, so the request fails only when the upload fails.
+    //When only the logging fails, the URL is still returned with `status` set to `"partial"`
+    //(`code` is `partial_failure`) and the backends which failed listed in `failures`.
+    //If the logging was skipped only because of open circuit breakers, `status` is `"degraded"` instead
+    //(`code` is `circuit_open`). Either way, the logs are queued in the outbox.
+    fn to_response(&self) -> http::Result<http::Response<String>> {
+        let failures = self.failures();
+        for (backend, e) in &failures {
+            warn!("failed to write to {}: {}", backend, error_chain(e));
+        }
+        let degraded = failures
+            .iter()
+            .all(|(_, e)| matches!(e, Error::CircuitOpen { .. }));
+        let failures = failures
+            .into_iter()
+            .map(|(backend, e)| Failure {
+                backend: backend.to_string(),
+                code: e.code().to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        let url = Some(self.url.clone());
+        let res = if failures.is_empty() {
+            Response::new("success".to_string(), url)
+        } else if degraded {
+            Response {
+                status: "degraded".to_string(),
+                url,
+                code: Some("circuit_open".to_string()),
+                message: None,
+                failures,
+            }
+        } else {
+            Response {
+                status: "partial".to_string(),
+                url,
+                code: Some("partial_failure".to_string()),
+                message: None,
+                failures,
+            }
+        };
+        http::Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(res.to_json_pretty())
+    }
+}
+
+//Uploads the image unless it already exists, and returns a presigned URL to it.
+async fn upload(
+    state: &State,
+    filename: &str,
+    (width, height): (u32, u32),
+    color: &Color,
+    format: &Format,
+) -> Result<String> {
+    //If the existence can't be checked, the image is uploaded anyway, which overwrites it with the same content.
+    let exists = state.s3.exists(filename).await.unwrap_or_else(|e| {
+        warn!(
+            "failed to check if {} exists: {}",
+            filename,
+            error_chain(&e)
+        );
+        false
+    });
+    if exists {
+        info!("{} already exists, skipping the upload", filename);
+    } else {
+        //Encoding a large image takes a while, so it is done off the async worker threads.
+        let (color, format) = (color.clone(), *format);
+        let image = match tokio::task::spawn_blocking(move || {
+            Image::create_image(width, height, &color, &format)
+        })
+        .await
+        {
+            Ok(image) => image?,
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        };
+        state
+            .s3
+            .upload(filename, image, format.content_type())
+            .await?;
+    }
+    //A URL is issued for each request even when the object is shared.
+    //If it fails, the object is kept as is: another request for the same image may have uploaded it too,
+    //and be referring to it with its URL.
+    state
+        .s3
+        .get_presigned_url(filename, state.config.s3.expiration_sec)
+        .await
+}
+
 async fn handler_logic(
     color: &Color,
-    config: Arc<Config>,
-    s3: Arc<Mutex<S3>>,
-    rds: Arc<Mutex<MySQL>>,
-    dynamodb: Arc<Mutex<DynamoDB>>,
-) -> Result<String, Box<dyn Error>> {
-    let image = Image::create_image(config.img_width, config.img_height, color);
+    size: (u32, u32),
+    format: &Format,
+    state: &State,
+) -> Result<Outcome> {
+    let filename = object_key(color, size.0, size.1, format);
 
-    let filename = create_filename();
+    //Recorded first so that the logs reach every sink once the image is uploaded, even if the server stops in the meantime.
+    let mut entry = state.outbox.record(&filename, color).await?;
 
-    let s3 = s3.lock().await;
-    s3.upload(&filename, image).await?;
-    let url = s3.get_presigned_url(&filename, config.s3.expiration_sec)?;
+    //The logs are written only for an image which has been uploaded, so a failed request leaves nothing behind.
+    //This costs latency: only the two log writes run concurrently, after the upload rather than alongside it.
+    let url = match upload(state, &filename, size, color, format).await {
+        Ok(url) => url,
+        Err(e) => {
+            if let Err(e) = state.outbox.discard(entry).await {
+                error!("{}", error_chain(&e));
+            }
+            return Err(e);
+        }
+    };
+    entry.uploaded = true;
 
-    rds.lock().await.insert(color)?;
+    let (rds, dynamodb) = tokio::join!(
+        Sink::Rds.write(state, color),
+        Sink::DynamoDB.write(state, color)
+    );
 
-    dynamodb.lock().await.insert(color).await?;
+    let max_attempts = state.outbox.max_attempts();
+    entry.record_attempt(Sink::Rds, &rds, max_attempts);
+    entry.record_attempt(Sink::DynamoDB, &dynamodb, max_attempts);
+    if let Err(e) = state.outbox.update(entry).await {
+        error!("{}", error_chain(&e));
+    }
 
-    Ok(url)
+    Ok(Outcome { url, rds, dynamodb })
 }
 
-async fn handler(
-    config: Arc<Config>,
-    s3: Arc<Mutex<S3>>,
-    rds: Arc<Mutex<MySQL>>,
-    dynamodb: Arc<Mutex<DynamoDB>>,
-    json_string: &str,
-) -> http::Result<http::Response<String>> {
+async fn handler(state: Arc<State>, json_string: &str) -> http::Result<http::Response<String>> {
     let req = Request::new(json_string);
 
     if let Err(e) = req {
-        info!("failed to parse json: {}", e);
-        return http::Response::builder()
-            .status(StatusCode::BAD_REQUEST)
-            .header("Content-Type", "application/json")
-            .body(Response::new("error".to_string(), None).to_json_pretty());
+        info!("{}", error_chain(&e));
+        return error_response(&e);
     }
 
     let req = req.unwrap();
-    let url = handler_logic(&Color::new(req.r, req.g, req.b), config, s3, rds, dynamodb).await;
-    if let Err(e) = url {
-        info!("aws operation failed: {}", e);
-        return http::Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .header("Content-Type", "application/json")
-            .body(Response::new("error".to_string(), None).to_json_pretty());
+    let (color, size, format) = match req.image_spec(&state.config) {
+        Ok(spec) => spec,
+        Err(e) => {
+            info!("{}", error_chain(&e));
+            return error_response(&e);
+        }
+    };
+    match handler_logic(&color, size, &format, &state).await {
+        Ok(outcome) => outcome.to_response(),
+        Err(e) => {
+            info!("request failed: {}", error_chain(&e));
+            error_response(&e)
+        }
+    }
+}
+
+//Longer keys are rejected so that they fit in any store.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+//Identifies a request body, so that a key repeated for another request can be detected.
+fn fingerprint(json_string: &str) -> String {
+    format!("{:x}", Sha256::digest(json_string.as_bytes()))
+}
+
+//Releases a reserved idempotency key when dropped before `disarm()` is called,
+//i.e. when the request is cancelled (e.g. by the client disconnecting), so that the key isn't stuck in progress for the whole window.
+//The cancelled request may still have its logs delivered by the outbox, which a retry with the key logs again.
+struct ReservedKey {
+    idempotency: Arc<dyn IdempotencyStore>,
+    key: Option<String>,
+}
+
+impl ReservedKey {
+    fn disarm(mut self) {
+        self.key = None;
+    }
+}
+
+impl Drop for ReservedKey {
+    fn drop(&mut self) {
+        let (Some(key), Ok(runtime)) = (self.key.take(), tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        let idempotency = self.idempotency.clone();
+        runtime.spawn(async move {
+            if let Err(e) = idempotency.release(&key).await {
+                warn!(
+                    "failed to release the idempotency key {}: {}",
+                    key,
+                    error_chain(&e)
+                );
+            }
+        });
     }
+}
 
+//Handles a request with an `Idempotency-Key` header.
+//The response to the first request with a key is stored for `idempotency.window_sec` seconds
+//and returned as is to the requests repeating the key, without writing anything again.
+//The key is released when the request fails, so that it can be retried with the same key.
+//This never logs twice, as a failed request logs nothing.
+async fn idempotent_handler(
+    state: Arc<State>,
+    key: &str,
+    json_string: &str,
+) -> http::Result<http::Response<String>> {
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+        return error_response(&Error::invalid_request(
+            "invalid Idempotency-Key",
+            format!("must be 1 to {} bytes long", MAX_IDEMPOTENCY_KEY_LEN),
+        ));
+    }
+
+    let window_sec = state.config.idempotency.window_sec;
+    let fingerprint = fingerprint(json_string);
+    match state
+        .idempotency
+        .reserve(key, &fingerprint, window_sec)
+        .await
+    {
+        Err(e) => {
+            info!("request failed: {}", error_chain(&e));
+            return error_response(&e);
+        }
+        Ok(Reservation::InProgress) => return error_response(&Error::IdempotencyKeyInUse),
+        Ok(Reservation::Done {
+            fingerprint: stored,
+            ..
+        }) if stored != fingerprint => return error_response(&Error::IdempotencyKeyReused),
+        Ok(Reservation::Done { response, .. }) => {
+            return http::Response::builder()
+                .status(response.status)
+                .header("Content-Type", "application/json")
+                .header("Idempotent-Replayed", "true")
+                .body(response.body);
+        }
+        Ok(Reservation::New) => (),
+    }
+    let reserved = ReservedKey {
+        idempotency: state.idempotency.clone(),
+        key: Some(key.to_string()),
+    };
+
+    let res = handler(state.clone(), json_string).await;
+    let stored = match &res {
+        Ok(res) if res.status() == StatusCode::OK => {
+            let response = StoredResponse {
+                status: res.status().as_u16(),
+                body: res.body().clone(),
+            };
+            state
+                .idempotency
+                .complete(key, &fingerprint, &response, window_sec)
+                .await
+        }
+        _ => state.idempotency.release(key).await,
+    };
+    reserved.disarm();
+    if let Err(e) = stored {
+        warn!(
+            "failed to update the idempotency key {}: {}",
+            key,
+            error_chain(&e)
+        );
+    }
+    res
+}
+
+#[derive(Deserialize)]
+struct OutboxQuery {
+    status: Option<outbox::Status>,
+}
+
+//Whether `authorization` is `Bearer <token>`.
+//The digests are compared in constant time, so that the time taken tells nothing about the token.
+fn is_authorized(token: &Secret, authorization: Option<&str>) -> bool {
+    let Some(given) = authorization.and_then(|a| a.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    Sha256::digest(given.as_bytes())
+        .iter()
+        .zip(Sha256::digest(token.expose().as_bytes()).iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+//Lists the outbox entries which are not yet delivered to every sink, for operators holding `admin_token`.
+//Without `admin_token`, the route doesn't exist.
+fn outbox_handler(
+    state: &State,
+    authorization: Option<&str>,
+    query: OutboxQuery,
+) -> http::Result<http::Response<String>> {
+    match &state.config.admin_token {
+        None => return error_response(&Error::not_found("no such route")),
+        Some(token) if !is_authorized(token, authorization) => {
+            return error_response(&Error::Unauthorized).map(|mut res| {
+                res.headers_mut().insert(
+                    http::header::WWW_AUTHENTICATE,
+                    http::HeaderValue::from_static("Bearer"),
+                );
+                res
+            });
+        }
+        Some(_) => (),
+    }
     http::Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/json")
-        .body(Response::new("success".to_string(), Some(url.unwrap())).to_json_pretty())
+        .body(serde_json::to_string_pretty(&state.outbox.list(query.status)).unwrap())
+}
+
+//Whether `content_type` is JSON, ignoring parameters such as `charset`.
+fn is_json(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .unwrap()
+        .trim()
+        .eq_ignore_ascii_case("application/json")
+}
+
+//Handles `POST /v1/images` (and `POST /`).
+async fn create_handler(
+    state: Arc<State>,
+    content_type: Option<String>,
+    body: bytes::Bytes,
+    key: Option<String>,
+) -> http::Result<http::Response<String>> {
+    let content_type = content_type.unwrap_or_default();
+    if !is_json(&content_type) {
+        return error_response(&Error::UnsupportedMediaType { content_type });
+    }
+    let json_string = match String::from_utf8(body.to_vec()) {
+        Ok(s) => s,
+        Err(e) => return error_response(&Error::invalid_request("body is not UTF-8", e)),
+    };
+    match key {
+        Some(key) => idempotent_handler(state, &key, &json_string).await,
+        None => handler(state, &json_string).await,
+    }
+}
+
+//Handles `GET /v1/images/{id}`, issuing a new URL of an image created before.
+async fn image_handler(state: Arc<State>, id: &str) -> http::Result<http::Response<String>> {
+    if !is_object_key(id) {
+        return error_response(&Error::not_found(format!("no such image: {}", id)));
+    }
+    let url = match state.s3.exists(id).await {
+        Ok(true) => {
+            state
+                .s3
+                .get_presigned_url(id, state.config.s3.expiration_sec)
+                .await
+        }
+        Ok(false) => Err(Error::not_found(format!("no such image: {}", id))),
+        Err(e) => Err(e),
+    };
+    match url {
+        Ok(url) => http::Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Response::new("success".to_string(), Some(url)).to_json_pretty()),
+        Err(e) => {
+            info!("request failed: {}", error_chain(&e));
+            error_response(&e)
+        }
+    }
+}
+
+//Longer ranges are rejected, as DynamoDB is queried once per day in the range.
+const MAX_COLORS_RANGE_DAYS: u64 = 31;
+
+//10000-01-01T00:00:00Z. Later times can't be turned into dates.
+const MAX_COLORS_TIME_MS: u64 = 253_402_300_800_000;
+
+//Epoch millis. `to` defaults to now, and `from` to a day before `to`.
+#[derive(Deserialize)]
+struct ColorsQuery {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ColorsResponse {
+    status: String,
+    colors: Vec<Color>,
+}
+
+//Handles `GET /v1/colors`, listing the colors logged to DynamoDB in `[from, to)`, oldest first.
+async fn colors_handler(
+    state: Arc<State>,
+    query: ColorsQuery,
+) -> http::Result<http::Response<String>> {
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+    if [query.from, query.to]
+        .into_iter()
+        .flatten()
+        .any(|t| t > MAX_COLORS_TIME_MS)
+    {
+        return error_response(&Error::invalid_request(
+            "invalid range",
+            format!(
+                "`from` and `to` must be at most {} (10000-01-01T00:00:00Z)",
+                MAX_COLORS_TIME_MS
+            ),
+        ));
+    }
+    let to = query.to.map_or_else(SystemTime::now, |to| {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(to)
+    });
+    let from = query.from.map_or_else(
+        || to.checked_sub(DAY).unwrap_or(SystemTime::UNIX_EPOCH),
+        |from| SystemTime::UNIX_EPOCH + Duration::from_millis(from),
+    );
+    if to
+        .duration_since(from)
+        .map_or(true, |d| d > DAY * MAX_COLORS_RANGE_DAYS as u32)
+    {
+        return error_response(&Error::invalid_request(
+            "invalid range",
+            format!(
+                "`from` must be before `to` by at most {} days",
+                MAX_COLORS_RANGE_DAYS
+            ),
+        ));
+    }
+
+    match state.dynamodb.select_by_time_range(from, to).await {
+        Ok(colors) => http::Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(
+                serde_json::to_string_pretty(&ColorsResponse {
+                    status: "success".to_string(),
+                    colors,
+                })
+                .unwrap(),
+            ),
+        Err(e) => {
+            info!("request failed: {}", error_chain(&e));
+            error_response(&e)
+        }
+    }
+}
+
+//The same as `Request`, except that `width` and `height` are shortened to `w` and `h`.
+#[derive(Deserialize)]
+struct RenderQuery {
+    color: Option<String>,
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+    a: Option<u8>,
+    w: Option<u32>,
+    h: Option<u32>,
+    format: Option<FormatName>,
+    quality: Option<u8>,
+    compression: Option<PngCompression>,
+}
+
+impl From<RenderQuery> for Request {
+    fn from(query: RenderQuery) -> Self {
+        Self {
+            color: query.color,
+            r: query.r,
+            g: query.g,
+            b: query.b,
+            a: query.a,
+            width: query.w,
+            height: query.h,
+            format: query.format,
+            quality: query.quality,
+            compression: query.compression,
+        }
+    }
+}
+
+//Whether `If-None-Match` lists `etag` (weak comparison, as only the content matters).
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+//Handles `GET /v1/render` (and `GET /render`), returning the image itself without storing or logging it.
+//The image is determined by the query alone, so it can be cached forever.
+async fn render_handler(
+    state: Arc<State>,
+    query: RenderQuery,
+    if_none_match: Option<String>,
+) -> warp::reply::Response {
+    let (color, (width, height), format) = match Request::from(query).image_spec(&state.config) {
+        Ok(spec) => spec,
+        Err(e) => return error_response(&e).into_response(),
+    };
+
+    let etag = format!("\"{}\"", object_key(&color, width, height, &format));
+    let builder = http::Response::builder()
+        .header("ETag", &etag)
+        .header("Cache-Control", "public, max-age=31536000, immutable");
+    if if_none_match.is_some_and(|v| etag_matches(&v, &etag)) {
+        return builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(bytes::Bytes::new())
+            .into_response();
+    }
+
+    //Encoding a large image takes a while, so it is done off the async worker threads.
+    let image =
+        tokio::task::spawn_blocking(move || Image::create_image(width, height, &color, &format))
+            .await;
+    match image {
+        Ok(Ok(image)) => builder
+            .status(StatusCode::OK)
+            .header("Content-Type", format.content_type())
+            .body(image)
+            .into_response(),
+        Ok(Err(e)) => {
+            info!("request failed: {}", error_chain(&e));
+            error_response(&e).into_response()
+        }
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+//Turns the requests no route accepts into errors of the same shape as the other responses.
+async fn rejection_handler(
+    r: Rejection,
+) -> std::result::Result<http::Result<http::Response<String>>, Infallible> {
+    let e = if r.is_not_found() {
+        Error::not_found("no such route")
+    } else if r.find::<reject::MethodNotAllowed>().is_some() {
+        Error::MethodNotAllowed
+    } else if let Some(e) = r.find::<reject::InvalidQuery>() {
+        Error::invalid_request("invalid query", e.to_string())
+    } else {
+        //The details may expose the internals, so they are only logged.
+        info!("rejected: {:?}", r);
+        Error::InvalidRequest {
+            message: "the request was rejected".to_string(),
+            source: None,
+        }
+    };
+    Ok(error_response(&e))
 }
 
 /*-------------------------------------*/
 
-pub async fn listen(config: &Arc<Config>) -> Result<(), Box<dyn Error>> {
-    let s3 = Arc::new(Mutex::new(S3::new(&config.s3).await?));
-    let rds = Arc::new(Mutex::new(MySQL::new(&config.rds)?));
-    let dynamodb = Arc::new(Mutex::new(DynamoDB::new(&config.dynamodb).await?));
+fn with_state(
+    state: watch::Receiver<Arc<State>>,
+) -> impl Filter<Extract = (Arc<State>,), Error = Infallible> + Clone {
+    warp::any().map(move || state.borrow().clone())
+}
 
-    let filter = warp::path!()
-        .and(header::exact_ignore_case(
-            "Content-Type",
-            "application/json",
-        ))
+//Builds the warp filter serving the API.
+//Each request uses the latest `State` published to `state` at the time it arrives.
+//The paths are matched before the methods, so that an unknown path is 404 rather than 405.
+pub fn routes(
+    state: watch::Receiver<Arc<State>>,
+) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+    //`POST /` is the API before versioning, kept as an alias.
+    let create = warp::path!("v1" / "images")
+        .or(warp::path!())
+        .unify()
+        .and(warp::post())
+        .and(header::optional::<String>("Content-Type"))
         .and(body::bytes())
+        .and(header::optional::<String>("Idempotency-Key"))
+        .and(with_state(state.clone()))
         //ref: |https://stackoverflow.com/questions/66111599/how-can-i-achieve-shared-application-state-with-warp-async-routes|
-        .and_then({
-            let s3 = s3.clone();
-            let rds = rds.clone();
-            let dynamodb = dynamodb.clone();
-            let config = config.clone();
-            move |b: bytes::Bytes| {
-                let s3 = s3.clone();
-                let rds = rds.clone();
-                let dynamodb = dynamodb.clone();
-                let config = config.clone();
-                async move {
-                    let json_string = String::from_utf8(b.into_iter().collect()).unwrap();
-                    handler(config, s3, rds, dynamodb, &json_string)
-                        .await
-                        .map_err(|_| warp::reject::reject())
-                }
-            }
+        .and_then(|content_type, b, key, state| async move {
+            create_handler(state, content_type, b, key)
+                .await
+                .map_err(|_| reject::reject())
+        });
+
+    let image = warp::path!("v1" / "images" / String)
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(|id: String, state| async move {
+            image_handler(state, &id)
+                .await
+                .map_err(|_| reject::reject())
+        });
+
+    let colors = warp::path!("v1" / "colors")
+        .and(warp::get())
+        .and(warp::query::<ColorsQuery>())
+        .and(with_state(state.clone()))
+        .and_then(|query, state| async move {
+            colors_handler(state, query)
+                .await
+                .map_err(|_| reject::reject())
+        });
+
+    let render = warp::path!("v1" / "render")
+        .or(warp::path!("render"))
+        .unify()
+        .and(warp::get())
+        .and(warp::query::<RenderQuery>())
+        .and(header::optional::<String>("If-None-Match"))
+        .and(with_state(state.clone()))
+        .then(|query, if_none_match, state| render_handler(state, query, if_none_match));
+
+    //`GET /outbox` is kept as an alias.
+    let outbox = warp::path!("v1" / "outbox")
+        .or(warp::path!("outbox"))
+        .unify()
+        .and(warp::get())
+        .and(warp::query::<OutboxQuery>())
+        .and(header::optional::<String>("Authorization"))
+        .and(with_state(state))
+        .map(|query, authorization: Option<String>, state: Arc<State>| {
+            outbox_handler(&state, authorization.as_deref(), query)
         });
 
+    create
+        .or(image)
+        .or(colors)
+        .or(render)
+        .or(outbox)
+        .recover(rejection_handler)
+}
+
+//Serves the API until the process is killed.
+pub async fn serve(state: watch::Receiver<Arc<State>>) {
+    let port = state.borrow().config.port;
+
     let logger = warp::log::custom(|info| {
         println!();
         info!(
@@ -165,13 +916,45 @@ pub async fn listen(config: &Arc<Config>) -> Result<(), Box<dyn Error>> {
             info.method(),
             info.path(),
             info.status(),
-            info.user_agent().unwrap(),
+            info.user_agent().unwrap_or("-"),
         );
     });
 
-    warp::serve(filter.with(logger))
-        .run(([0, 0, 0, 0], config.port))
+    warp::serve(routes(state).with(logger))
+        .run(([0, 0, 0, 0], port))
         .await;
+}
+
+//Serves the API with the backends selected by `backend`, reloading `config_file` whenever it is modified or SIGHUP is received.
+pub async fn listen(config_file: &str) -> Result<()> {
+    let config = Config::new(config_file)?;
+    config.validate()?;
+    info!("loaded {}: {}", config_file, config.to_redacted_json());
+
+    match config.backend {
+        Backend::Aws => listen_with(config_file, config, AwsBackendBuilder).await,
+        Backend::Local => {
+            let builder = LocalBackendBuilder {
+                dir: config.local.dir.clone().into(),
+            };
+            listen_with(config_file, config, builder).await
+        }
+        Backend::Memory => listen_with(config_file, config, MemoryBackendBuilder).await,
+    }
+}
+
+async fn listen_with<B: BackendBuilder + 'static>(
+    config_file: &str,
+    config: Config,
+    builder: B,
+) -> Result<()> {
+    let state = State::build(config, &builder).await?;
+    let (sender, receiver) = watch::channel(Arc::new(state));
+
+    tokio::spawn(Reloader::new(config_file, builder, sender).run());
+    tokio::spawn(outbox::run(receiver.clone()));
+
+    serve(receiver).await;
 
     Ok(())
 }
@@ -181,62 +964,108 @@ pub async fn listen(config: &Arc<Config>) -> Result<(), Box<dyn Error>> {
 #[cfg(test)]
 mod handler_tests {
 
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use async_trait::async_trait;
+    use bytes::Bytes;
+
     use super::*;
+    use crate::backend::{KeyValueLog, ObjectStorage, RelationalLog};
+    use crate::blocking::BlockingPool;
+    use crate::breaker::Breaking;
+    use crate::config::CircuitBreakerConfig;
+    use crate::memory::{
+        MemoryIdempotencyStore, MemoryKeyValueLog, MemoryObjectStorage, MemoryRelationalLog,
+    };
+    use crate::outbox::{retry_pending, Outbox, Status};
+
+    fn config() -> Arc<Config> {
+        Arc::new(
+            serde_json::from_str(
+                r#"{
+                    "port": 30021,
+                    "img_width": 30,
+                    "img_height": 20,
+                    "s3": {"bucket_name": "bucket", "expiration_sec": 30},
+                    "rds": {
+                        "host": "localhost",
+                        "port": 3306,
+                        "user": "user",
+                        "password": "password",
+                        "database_name": "test",
+                        "table_name": "colors"
+                    },
+                    "dynamodb": {"table_name": "colors"}
+                }"#,
+            )
+            .unwrap(),
+        )
+    }
+
+    type Backends = (
+        Arc<Config>,
+        Arc<MemoryObjectStorage>,
+        Arc<MemoryRelationalLog>,
+        Arc<MemoryKeyValueLog>,
+    );
+
+    fn state(
+        config: Arc<Config>,
+        s3: Arc<impl ObjectStorage + 'static>,
+        rds: Arc<impl RelationalLog + 'static>,
+        dynamodb: Arc<impl KeyValueLog + 'static>,
+    ) -> Arc<State> {
+        Arc::new(State {
+            config,
+            s3,
+            rds,
+            dynamodb,
+            outbox: Arc::new(Outbox::in_memory(3)),
+            idempotency: Arc::new(MemoryIdempotencyStore::new()),
+        })
+    }
 
-    async fn f() -> Result<
+    fn f() -> Backends {
         (
-            Arc<Config>,
-            Arc<Mutex<S3>>,
-            Arc<Mutex<MySQL>>,
-            Arc<Mutex<DynamoDB>>,
-        ),
-        Box<dyn Error>,
-    > {
-        let config = Arc::new(Config::new("./config.json"));
-        let s3 = Arc::new(Mutex::new(S3::new(&config.s3).await?));
-        let rds = Arc::new(Mutex::new(MySQL::new(&config.rds)?));
-        let dynamodb = Arc::new(Mutex::new(DynamoDB::new(&config.dynamodb).await?));
-        Ok((config, s3, rds, dynamodb))
+            config(),
+            Arc::new(MemoryObjectStorage::new()),
+            Arc::new(MemoryRelationalLog::new()),
+            Arc::new(MemoryKeyValueLog::new()),
+        )
     }
 
     #[tokio::test]
-    async fn test01() -> Result<(), Box<dyn Error>> {
-        let (config, s3, rds, dynamodb) = f().await?;
+    async fn test01() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
 
-        let res = handler(config, s3, rds, dynamodb, "").await;
+        let res = handler(state(config, s3.clone(), rds, dynamodb), "").await;
         println!("{:?}", res);
         assert!(res.is_ok());
 
         let res = res.unwrap();
         assert_eq!(StatusCode::BAD_REQUEST, res.status());
         assert_eq!(
-            "{\n  \"status\": \"error\",\n  \"url\": null\n}",
+            "{\n  \"status\": \"error\",\n  \"url\": null,\n  \"code\": \"invalid_request\",\n  \"message\": \"invalid request: failed to parse json: EOF while parsing a value at line 1 column 0\"\n}",
             res.body()
         );
+        assert!(s3.is_empty());
 
         Ok(())
     }
 
     #[tokio::test]
-    async fn test02() -> Result<(), Box<dyn Error>> {
-        let (config, s3, rds, dynamodb) = f().await?;
+    async fn test02() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
 
         let color = Color::new(100, 50, 25);
 
-        let num_rds_row = rds.clone().lock().await.select_by_color(&color)?.len();
-        let num_dynamodb_entry = dynamodb
-            .clone()
-            .lock()
-            .await
-            .select_by_color(&color)
-            .await?
-            .len();
+        let num_rds_row = rds.select_by_color(&color).await?.len();
+        let num_dynamodb_entry = dynamodb.select_by_color(&color).await?.len();
 
+        let state = state(config, s3.clone(), rds.clone(), dynamodb.clone());
         let res = handler(
-            config,
-            s3,
-            rds.clone(),
-            dynamodb.clone(),
+            state.clone(),
             &format!(
                 r#"{{"r": {}, "g": {}, "b": {}}}"#,
                 color.r, color.g, color.b
@@ -250,23 +1079,1059 @@ mod handler_tests {
         assert_eq!(StatusCode::OK, res.status());
         assert!(res
             .body()
-            .starts_with("{\n  \"status\": \"success\",\n  \"url\": \"https://"));
+            .starts_with("{\n  \"status\": \"success\",\n  \"url\": \"memory://"));
+        assert_eq!(1, s3.len());
 
+        assert_eq!(num_rds_row + 1, rds.select_by_color(&color).await?.len());
         assert_eq!(
-            num_rds_row + 1,
-            rds.lock().await.select_by_color(&color)?.len()
+            num_dynamodb_entry + 1,
+            dynamodb.select_by_color(&color).await?.len(),
         );
+        assert!(state.outbox.list(None).is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test03() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let (_sender, receiver) = watch::channel(state(config, s3.clone(), rds.clone(), dynamodb));
+        let filter = routes(receiver);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/")
+            .header("Content-Type", "application/json")
+            .body(r#"{"r": 1, "g": 2, "b": 3}"#)
+            .reply(&filter)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(1, s3.len());
+        assert_eq!(1, rds.select_by_color(&Color::new(1, 2, 3)).await?.len());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/")
+            .header("Content-Type", "text/plain")
+            .body(r#"{"r": 1, "g": 2, "b": 3}"#)
+            .reply(&filter)
+            .await;
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, res.status());
+        assert_eq!(1, s3.len());
+
+        Ok(())
+    }
+
+    struct FailingRelationalLog;
+
+    #[async_trait]
+    impl RelationalLog for FailingRelationalLog {
+        async fn insert(&self, _color: &Color) -> Result<()> {
+            Err(Error::rds("insert failed", "connection refused"))
+        }
+
+        async fn select_by_color(&self, _color: &Color) -> Result<Vec<Color>> {
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn test04() -> Result<()> {
+        let (config, s3, _, dynamodb) = f();
+
+        let state = state(
+            config,
+            s3.clone(),
+            Arc::new(FailingRelationalLog),
+            dynamodb.clone(),
+        );
+        let res = handler(state.clone(), r#"{"r": 1, "g": 2, "b": 3}"#)
+            .await
+            .unwrap();
+        //The image is still available, so only the RDS write is reported as failed.
+        assert_eq!(StatusCode::OK, res.status());
+        let body: serde_json::Value = serde_json::from_str(res.body()).unwrap();
+        assert_eq!("partial", body["status"]);
+        assert_eq!("partial_failure", body["code"]);
+        assert!(body["url"].as_str().unwrap().starts_with("memory://"));
         assert_eq!(
-            num_dynamodb_entry + 1,
-            dynamodb
-                .clone()
-                .lock()
+            serde_json::json!([{"backend": "rds", "code": "rds_failed"}]),
+            body["failures"]
+        );
+        assert_eq!(1, s3.len());
+        assert_eq!(
+            1,
+            dynamodb.select_by_color(&Color::new(1, 2, 3)).await?.len()
+        );
+
+        //The RDS write is left in the outbox to be retried.
+        let pending = state.outbox.list(Some(Status::Pending));
+        assert_eq!(1, pending.len());
+        assert_eq!(vec![Sink::Rds], pending[0].pending_sinks());
+
+        Ok(())
+    }
+
+    struct FailingObjectStorage;
+
+    #[async_trait]
+    impl ObjectStorage for FailingObjectStorage {
+        async fn upload(&self, _filename: &str, _image: Bytes, _content_type: &str) -> Result<()> {
+            Err(Error::storage("upload failed", "access denied"))
+        }
+
+        async fn exists(&self, _filename: &str) -> Result<bool> {
+            Ok(false)
+        }
+
+        async fn get_presigned_url(
+            &self,
+            _filename: &str,
+            _expiration_secs: u32,
+        ) -> Result<String> {
+            Err(Error::storage("presign failed", "no such object"))
+        }
+
+        async fn delete(&self, _filename: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    //Without the image the request fails, and its outbox entry is discarded without writing the logs.
+    #[tokio::test]
+    async fn test07() -> Result<()> {
+        let (config, _, rds, dynamodb) = f();
+        let state = state(
+            config,
+            Arc::new(FailingObjectStorage),
+            rds.clone(),
+            dynamodb.clone(),
+        );
+
+        let res = handler(state.clone(), r#"{"r": 1, "g": 2, "b": 3}"#)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, res.status());
+        assert_eq!(
+            serde_json::json!({
+                "status": "error",
+                "url": null,
+                "code": "storage_failed",
+            }),
+            serde_json::from_str::<serde_json::Value>(res.body()).unwrap()
+        );
+
+        //Nothing is logged for the image which was not uploaded, neither now nor later.
+        retry_pending(&state).await;
+        assert!(rds.select_by_color(&Color::new(1, 2, 3)).await?.is_empty());
+        assert!(dynamodb
+            .select_by_color(&Color::new(1, 2, 3))
+            .await?
+            .is_empty());
+        assert!(state.outbox.list(None).is_empty());
+
+        Ok(())
+    }
+
+    //Blocks for a while in each insert, like the synchronous `mysql` driver talking to a slow server.
+    struct SlowRelationalLog {
+        blocking: BlockingPool,
+    }
+
+    #[async_trait]
+    impl RelationalLog for SlowRelationalLog {
+        async fn insert(&self, _color: &Color) -> Result<()> {
+            self.blocking
+                .run(|| std::thread::sleep(Duration::from_millis(300)))
+                .await;
+            Ok(())
+        }
+
+        async fn select_by_color(&self, _color: &Color) -> Result<Vec<Color>> {
+            Ok(vec![])
+        }
+    }
+
+    //Even on a single-threaded runtime, a slow insert doesn't hold up other requests.
+    #[tokio::test(flavor = "current_thread")]
+    async fn test05() -> Result<()> {
+        let (config, s3, _, dynamodb) = f();
+        let rds = Arc::new(SlowRelationalLog {
+            blocking: BlockingPool::new(4),
+        });
+        let state = state(config, s3, rds, dynamodb);
+
+        let body = r#"{"r": 1, "g": 2, "b": 3}"#;
+        let start = Instant::now();
+        let (a, b, c) = tokio::join!(
+            handler(state.clone(), body),
+            handler(state.clone(), body),
+            handler(state, body),
+        );
+        let elapsed = start.elapsed();
+        for res in [a, b, c] {
+            assert_eq!(StatusCode::OK, res.unwrap().status());
+        }
+        //Three 300ms inserts one after another would take at least 900ms.
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+
+        Ok(())
+    }
+
+    //Records how many calls were in progress at the same time.
+    #[derive(Default)]
+    struct InFlight {
+        current: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    impl InFlight {
+        async fn hold(&self) {
+            let n = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(n, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            self.current.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        fn max(&self) -> usize {
+            self.max.load(Ordering::SeqCst)
+        }
+    }
+
+    #[derive(Default)]
+    struct SlowObjectStorage {
+        inner: MemoryObjectStorage,
+        in_flight: InFlight,
+    }
+
+    #[async_trait]
+    impl ObjectStorage for SlowObjectStorage {
+        async fn upload(&self, filename: &str, image: Bytes, content_type: &str) -> Result<()> {
+            self.in_flight.hold().await;
+            self.inner.upload(filename, image, content_type).await
+        }
+
+        async fn exists(&self, filename: &str) -> Result<bool> {
+            self.inner.exists(filename).await
+        }
+
+        async fn get_presigned_url(&self, filename: &str, expiration_secs: u32) -> Result<String> {
+            self.in_flight.hold().await;
+            self.inner
+                .get_presigned_url(filename, expiration_secs)
+                .await
+        }
+
+        async fn delete(&self, filename: &str) -> Result<()> {
+            self.inner.delete(filename).await
+        }
+    }
+
+    #[derive(Default)]
+    struct SlowKeyValueLog {
+        inner: MemoryKeyValueLog,
+        in_flight: InFlight,
+    }
+
+    #[async_trait]
+    impl KeyValueLog for SlowKeyValueLog {
+        async fn insert(&self, color: &Color) -> Result<()> {
+            self.in_flight.hold().await;
+            self.inner.insert(color).await
+        }
+
+        async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+            self.inner.select_by_color(color).await
+        }
+
+        async fn select_by_time_range(
+            &self,
+            from: SystemTime,
+            to: SystemTime,
+        ) -> Result<Vec<Color>> {
+            self.inner.select_by_time_range(from, to).await
+        }
+    }
+
+    //Load test: requests served concurrently use S3 and DynamoDB at the same time.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test06() -> Result<()> {
+        const NUM_REQUEST: usize = 32;
+
+        let (config, _, rds, _) = f();
+        let s3 = Arc::new(SlowObjectStorage::default());
+        let dynamodb = Arc::new(SlowKeyValueLog::default());
+        let (_sender, receiver) = watch::channel(state(config, s3.clone(), rds, dynamodb.clone()));
+        let routes = routes(receiver);
+
+        let start = Instant::now();
+        let mut tasks = tokio::task::JoinSet::new();
+        for i in 0..NUM_REQUEST {
+            let routes = routes.clone();
+            tasks.spawn(async move {
+                warp::test::request()
+                    .method("POST")
+                    .header("Content-Type", "application/json")
+                    .body(format!(r#"{{"r": {}, "g": 2, "b": 3}}"#, i))
+                    .reply(&routes)
+                    .await
+            });
+        }
+        while let Some(res) = tasks.join_next().await {
+            assert_eq!(StatusCode::OK, res.unwrap().status());
+        }
+        let elapsed = start.elapsed();
+
+        assert_eq!(NUM_REQUEST, s3.in_flight.max());
+        assert_eq!(NUM_REQUEST, s3.inner.len());
+        assert_eq!(NUM_REQUEST, dynamodb.in_flight.max());
+        //Each request spends 300ms in the backends; serialized, they would take about 10s.
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test08() -> Result<()> {
+        let (config, s3, _, dynamodb) = f();
+        let state = state(config, s3, Arc::new(FailingRelationalLog), dynamodb);
+        let mut entry = state.outbox.record("a.png", &Color::new(1, 2, 3)).await?;
+        entry.uploaded = true;
+        state.outbox.update(entry).await?;
+        let (sender, receiver) = watch::channel(state.clone());
+        let routes = routes(receiver);
+        let get = |path: &str, authorization: &str| {
+            warp::test::request()
+                .path(path)
+                .header("Authorization", authorization)
+        };
+
+        //Disabled without `admin_token`.
+        let res = get("/v1/outbox", "Bearer ").reply(&routes).await;
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        let config = Config {
+            admin_token: Some(serde_json::from_str(r#""s3cret""#).unwrap()),
+            ..(*state.config).clone()
+        };
+        sender.send_replace(Arc::new(State {
+            config: Arc::new(config),
+            s3: state.s3.clone(),
+            rds: state.rds.clone(),
+            dynamodb: state.dynamodb.clone(),
+            outbox: state.outbox.clone(),
+            idempotency: state.idempotency.clone(),
+        }));
+        for authorization in ["", "Bearer ", "Bearer s3cre", "Bearer s3cret!", "s3cret"] {
+            let res = get("/v1/outbox", authorization).reply(&routes).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, res.status(), "{}", authorization);
+            assert_eq!("Bearer", res.headers()["WWW-Authenticate"]);
+        }
+        let get = |path: &str| get(path, "Bearer s3cret");
+
+        let res = get("/outbox?status=pending").reply(&routes).await;
+        assert_eq!(StatusCode::OK, res.status());
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!("a.png", body[0]["filename"]);
+        assert_eq!("pending", body[0]["deliveries"]["rds"]["status"]);
+
+        let res = get("/outbox?status=failed").reply(&routes).await;
+        assert_eq!(b"[]", res.body().as_ref());
+
+        Ok(())
+    }
+
+    //Accepts uploads but fails to presign them.
+    #[derive(Default)]
+    struct UnsignableObjectStorage {
+        inner: MemoryObjectStorage,
+    }
+
+    #[async_trait]
+    impl ObjectStorage for UnsignableObjectStorage {
+        async fn upload(&self, filename: &str, image: Bytes, content_type: &str) -> Result<()> {
+            self.inner.upload(filename, image, content_type).await
+        }
+
+        async fn exists(&self, filename: &str) -> Result<bool> {
+            self.inner.exists(filename).await
+        }
+
+        async fn get_presigned_url(
+            &self,
+            _filename: &str,
+            _expiration_secs: u32,
+        ) -> Result<String> {
+            Err(Error::storage("presign failed", "no credentials"))
+        }
+
+        async fn delete(&self, filename: &str) -> Result<()> {
+            self.inner.delete(filename).await
+        }
+    }
+
+    //An uploaded object is kept when its request fails, as it may be shared with other requests.
+    #[tokio::test]
+    async fn test09() -> Result<()> {
+        let (config, _, rds, dynamodb) = f();
+        let s3 = Arc::new(UnsignableObjectStorage::default());
+
+        let res = handler(
+            state(config, s3.clone(), rds.clone(), dynamodb),
+            r#"{"r": 1, "g": 2, "b": 3}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, res.status());
+        assert_eq!(1, s3.inner.len());
+        assert!(rds.select_by_color(&Color::new(1, 2, 3)).await?.is_empty());
+
+        Ok(())
+    }
+
+    //Once the circuit of RDS opens, it is skipped and the response is degraded rather than failed.
+    #[tokio::test]
+    async fn test10() -> Result<()> {
+        let (config, s3, _, dynamodb) = f();
+        let rds = Arc::new(Breaking::new(
+            "rds",
+            FailingRelationalLog,
+            &CircuitBreakerConfig {
+                failure_threshold: 1,
+                open_ms: 60_000,
+            },
+        ));
+        let state = state(config, s3, rds, dynamodb);
+
+        let mut statuses = vec![];
+        for _ in 0..2 {
+            let res = handler(state.clone(), r#"{"r": 1, "g": 2, "b": 3}"#)
                 .await
-                .select_by_color(&color)
+                .unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+            let body: serde_json::Value = serde_json::from_str(res.body()).unwrap();
+            assert!(body["url"].is_string());
+            statuses.push((body["status"].clone(), body["code"].clone()));
+        }
+        assert_eq!(
+            vec![
+                ("partial".into(), "partial_failure".into()),
+                ("degraded".into(), "circuit_open".into()),
+            ],
+            statuses
+        );
+
+        //Both log writes are queued, and the skipped one isn't counted as an attempt.
+        let attempts = state
+            .outbox
+            .list(Some(Status::Pending))
+            .iter()
+            .map(|e| e.deliveries[&Sink::Rds].attempts)
+            .collect::<Vec<_>>();
+        assert_eq!(vec![1, 0], attempts);
+
+        Ok(())
+    }
+
+    fn post(body: &str, key: Option<&str>) -> warp::test::RequestBuilder {
+        let req = warp::test::request()
+            .method("POST")
+            .header("Content-Type", "application/json")
+            .body(body);
+        match key {
+            Some(key) => req.header("Idempotency-Key", key),
+            None => req,
+        }
+    }
+
+    #[tokio::test]
+    async fn test11() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let state = state(config, s3.clone(), rds.clone(), dynamodb);
+        let (_sender, receiver) = watch::channel(state.clone());
+        let routes = routes(receiver);
+        let body = r#"{"r": 1, "g": 2, "b": 3}"#;
+
+        //A repeated key returns the original response without writing again.
+        let first = post(body, Some("key-1")).reply(&routes).await;
+        assert_eq!(StatusCode::OK, first.status());
+        let second = post(body, Some("key-1")).reply(&routes).await;
+        assert_eq!(StatusCode::OK, second.status());
+        assert_eq!(first.body(), second.body());
+        assert_eq!("true", second.headers()["Idempotent-Replayed"]);
+        assert_eq!(1, s3.len());
+        assert_eq!(1, rds.select_by_color(&Color::new(1, 2, 3)).await?.len());
+
+        //The key can't be used for another request.
+        let res = post(r#"{"r": 4, "g": 5, "b": 6}"#, Some("key-1"))
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+
+        //Nor while the first request with it is in progress.
+        state.idempotency.reserve("key-2", "", 60).await?;
+        let res = post(body, Some("key-2")).reply(&routes).await;
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        assert!(std::str::from_utf8(res.body())
+            .unwrap()
+            .contains("idempotency_key_in_use"));
+
+        let res = post(body, Some("")).reply(&routes).await;
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+        Ok(())
+    }
+
+    //The key of a failed or cancelled request is released, so that the request can be retried with it.
+    #[tokio::test]
+    async fn test12() -> Result<()> {
+        let (config, _, rds, dynamodb) = f();
+        let failing = state(
+            config.clone(),
+            Arc::new(FailingObjectStorage),
+            rds.clone(),
+            dynamodb.clone(),
+        );
+        let body = r#"{"r": 1, "g": 2, "b": 3}"#;
+
+        let res = idempotent_handler(failing.clone(), "key-1", body)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_GATEWAY, res.status());
+        assert_eq!(
+            Reservation::New,
+            failing
+                .idempotency
+                .reserve("key-1", &fingerprint(body), 60)
+                .await?
+        );
+        //The failed request has logged nothing, so the retry doesn't log twice.
+        assert!(rds.select_by_color(&Color::new(1, 2, 3)).await?.is_empty());
+
+        let state = state(
+            config,
+            Arc::new(SlowObjectStorage::default()),
+            rds,
+            dynamodb,
+        );
+        let res = tokio::time::timeout(
+            Duration::from_millis(50),
+            idempotent_handler(state.clone(), "key-2", body),
+        )
+        .await;
+        assert!(res.is_err());
+        //Released in the background.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            Reservation::New,
+            state
+                .idempotency
+                .reserve("key-2", &fingerprint(body), 60)
+                .await?
+        );
+
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct CountingObjectStorage {
+        inner: MemoryObjectStorage,
+        num_upload: AtomicUsize,
+        num_presign: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ObjectStorage for CountingObjectStorage {
+        async fn upload(&self, filename: &str, image: Bytes, content_type: &str) -> Result<()> {
+            self.num_upload.fetch_add(1, Ordering::SeqCst);
+            self.inner.upload(filename, image, content_type).await
+        }
+
+        async fn exists(&self, filename: &str) -> Result<bool> {
+            self.inner.exists(filename).await
+        }
+
+        async fn get_presigned_url(&self, filename: &str, expiration_secs: u32) -> Result<String> {
+            self.num_presign.fetch_add(1, Ordering::SeqCst);
+            self.inner
+                .get_presigned_url(filename, expiration_secs)
+                .await
+        }
+
+        async fn delete(&self, filename: &str) -> Result<()> {
+            self.inner.delete(filename).await
+        }
+    }
+
+    //The requests for the same image share one object, while each of them is still logged and given a URL.
+    #[tokio::test]
+    async fn test13() -> Result<()> {
+        let (config, _, rds, dynamodb) = f();
+        let s3 = Arc::new(CountingObjectStorage::default());
+        let state = state(config, s3.clone(), rds.clone(), dynamodb);
+
+        for body in [
+            r#"{"r": 255, "g": 128, "b": 0}"#,
+            r#"{"r": 255, "g": 128, "b": 0}"#,
+            r#"{"r": 0, "g": 128, "b": 255}"#,
+        ] {
+            let res = handler(state.clone(), body).await.unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+        }
+
+        assert_eq!(2, s3.num_upload.load(Ordering::SeqCst));
+        assert_eq!(3, s3.num_presign.load(Ordering::SeqCst));
+        assert_eq!(2, s3.inner.len());
+        assert!(s3.inner.get("ff8000-30x20.png").is_some());
+        assert_eq!(
+            2,
+            rds.select_by_color(&Color::new(255, 128, 0)).await?.len()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test14() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let (_sender, receiver) = watch::channel(state(config, s3.clone(), rds, dynamodb));
+        let routes = routes(receiver);
+        let request = |method: &str, path: &str| {
+            warp::test::request()
+                .method(method)
+                .path(path)
+                .header("Content-Type", "application/json; charset=utf-8")
+                .body(r#"{"r": 255, "g": 128, "b": 0}"#)
+        };
+        let body = |res: &warp::http::Response<bytes::Bytes>| -> serde_json::Value {
+            serde_json::from_slice(res.body()).unwrap()
+        };
+
+        let res = request("POST", "/v1/images").reply(&routes).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("success", body(&res)["status"]);
+        assert_eq!(1, s3.len());
+
+        let res = request("GET", "/v1/images/ff8000-30x20.png")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(body(&res)["url"]
+            .as_str()
+            .unwrap()
+            .starts_with("memory://ff8000-30x20.png"));
+
+        let res = request("GET", "/v1/colors?from=0").reply(&routes).await;
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        let res = request("GET", "/v1/colors").reply(&routes).await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            serde_json::json!({"status": "success", "colors": [{"r": 255, "g": 128, "b": 0}]}),
+            body(&res)
+        );
+
+        for (method, path, status, code) in [
+            ("GET", "/v1/images/000000-30x20.png", 404, "not_found"),
+            ("GET", "/v2/images", 404, "not_found"),
+            (
+                "POST",
+                "/v1/images/ff8000-30x20.png",
+                405,
+                "method_not_allowed",
+            ),
+            ("GET", "/v1/images", 405, "method_not_allowed"),
+            ("DELETE", "/", 405, "method_not_allowed"),
+        ] {
+            let res = request(method, path).reply(&routes).await;
+            assert_eq!(status, res.status().as_u16(), "{} {}", method, path);
+            let body = body(&res);
+            assert_eq!("error", body["status"], "{} {}", method, path);
+            assert_eq!(serde_json::Value::Null, body["url"], "{} {}", method, path);
+            assert_eq!(code, body["code"], "{} {}", method, path);
+        }
+
+        Ok(())
+    }
+
+    //Rendering stores and logs nothing, and is cached by the ETag.
+    #[tokio::test]
+    async fn test15() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let (_sender, receiver) = watch::channel(state(config, s3.clone(), rds.clone(), dynamodb));
+        let routes = routes(receiver);
+
+        let res = warp::test::request()
+            .path("/render?r=255&g=128&b=0&w=4&h=2")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("image/png", res.headers()["Content-Type"]);
+        assert_eq!("\"ff8000-4x2.png\"", res.headers()["ETag"]);
+        assert!(res.headers()["Cache-Control"]
+            .to_str()
+            .unwrap()
+            .contains("immutable"));
+        let image = ::image::load_from_memory(res.body()).unwrap().to_rgb8();
+        assert_eq!((4, 2), image.dimensions());
+        assert_eq!([255, 128, 0], image.get_pixel(3, 1).0);
+        assert_eq!(0, s3.len());
+        assert!(rds
+            .select_by_color(&Color::new(255, 128, 0))
+            .await?
+            .is_empty());
+
+        //The size defaults to the one in the config.
+        let res = warp::test::request()
+            .path("/v1/render?r=255&g=128&b=0")
+            .reply(&routes)
+            .await;
+        assert_eq!("\"ff8000-30x20.png\"", res.headers()["ETag"]);
+
+        for if_none_match in [r#""ff8000-4x2.png""#, r#"W/"a", W/"ff8000-4x2.png""#, "*"] {
+            let res = warp::test::request()
+                .path("/render?r=255&g=128&b=0&w=4&h=2")
+                .header("If-None-Match", if_none_match)
+                .reply(&routes)
+                .await;
+            assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+            assert_eq!("\"ff8000-4x2.png\"", res.headers()["ETag"]);
+            assert!(res.body().is_empty());
+        }
+        let res = warp::test::request()
+            .path("/render?r=255&g=128&b=0&w=4&h=2")
+            .header("If-None-Match", r#""ff8000-30x20.png""#)
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        for path in [
+            "/render?r=255&g=128",
+            "/render?r=256&g=128&b=0",
+            "/render?r=255&g=128&b=0&w=0",
+            "/render?r=255&g=128&b=0&h=4097",
+        ] {
+            let res = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", path);
+        }
+
+        Ok(())
+    }
+
+    //The format and its options determine the key and the content type.
+    #[tokio::test]
+    async fn test16() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let (_sender, receiver) = watch::channel(state(config, s3.clone(), rds, dynamodb));
+        let routes = routes(receiver);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/images")
+            .header("Content-Type", "application/json")
+            .body(r#"{"r": 255, "g": 128, "b": 0, "format": "jpeg", "quality": 90}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        let image = s3.get("ff8000-30x20-q90.jpg").unwrap();
+        assert_eq!(::image::ImageFormat::Jpeg, ::image::guess_format(&image)?);
+
+        let res = warp::test::request()
+            .path("/render?r=255&g=128&b=0&format=webp")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("image/webp", res.headers()["Content-Type"]);
+        assert_eq!("\"ff8000-30x20.webp\"", res.headers()["ETag"]);
+
+        for body in [
+            r#"{"r": 255, "g": 128, "b": 0, "format": "svg"}"#,
+            r#"{"r": 255, "g": 128, "b": 0, "format": "png", "quality": 90}"#,
+        ] {
+            let res = warp::test::request()
+                .method("POST")
+                .path("/v1/images")
+                .header("Content-Type", "application/json")
+                .body(body)
+                .reply(&routes)
+                .await;
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", body);
+        }
+        assert_eq!(1, s3.len());
+
+        Ok(())
+    }
+
+    //The size may be given for each request within the limits of the config.
+    #[tokio::test]
+    async fn test17() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let config = Arc::new(Config {
+            max_img_dimension: 100,
+            max_img_pixels: 5000,
+            ..(*config).clone()
+        });
+        let (_sender, receiver) = watch::channel(state(config, s3.clone(), rds, dynamodb));
+        let routes = routes(receiver);
+        let post = |body: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/v1/images")
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+
+        let res = post(r#"{"r": 255, "g": 128, "b": 0, "width": 100, "height": 50}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        let image = ::image::load_from_memory(&s3.get("ff8000-100x50.png").unwrap())?;
+        assert_eq!((100, 50), (image.width(), image.height()));
+
+        for (body, message) in [
+            (
+                r#"{"r": 255, "g": 128, "b": 0, "width": 101}"#,
+                "invalid request: invalid size: width 101 exceeds the limit of 100 (`max_img_dimension`)",
+            ),
+            (
+                r#"{"r": 255, "g": 128, "b": 0, "width": 100, "height": 51}"#,
+                "invalid request: invalid size: 100x51 (5100 pixels) exceeds the limit of 5000 pixels (`max_img_pixels`)",
+            ),
+            (
+                r#"{"r": 255, "g": 128, "b": 0, "height": 0}"#,
+                "invalid request: invalid size: height must be positive",
+            ),
+        ] {
+            let res = post(body).reply(&routes).await;
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", body);
+            let res = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+            assert_eq!("invalid_request", res["code"]);
+            assert_eq!(message, res["message"]);
+        }
+
+        let res = warp::test::request()
+            .path("/v1/render?r=255&g=128&b=0&w=100&h=60")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        assert_eq!(1, s3.len());
+
+        Ok(())
+    }
+
+    //Colors may be given as text, and those which are not opaque are encoded as RGBA.
+    #[tokio::test]
+    async fn test18() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let (_sender, receiver) = watch::channel(state(config, s3.clone(), rds, dynamodb.clone()));
+        let routes = routes(receiver);
+        let post = |body: &'static str| {
+            warp::test::request()
+                .method("POST")
+                .path("/v1/images")
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+
+        for (body, key) in [
+            (r#"{"color": "orange"}"#, "ffa500-30x20.png"),
+            (r#"{"color": "hsl(30, 100%, 50%)"}"#, "ff8000-30x20.png"),
+            (r##"{"color": "#ff800080"}"##, "ff800080-30x20.png"),
+            (
+                r#"{"r": 255, "g": 128, "b": 0, "a": 0, "format": "gif"}"#,
+                "ff800000-30x20.gif",
+            ),
+        ] {
+            let res = post(body).reply(&routes).await;
+            assert_eq!(StatusCode::OK, res.status(), "{}", body);
+            assert!(s3.get(key).is_some(), "{}", body);
+        }
+        let image = ::image::load_from_memory(&s3.get("ff800080-30x20.png").unwrap())?;
+        assert_eq!(::image::ColorType::Rgba8, image.color());
+        assert_eq!([255, 128, 0, 128], image.to_rgba8().get_pixel(0, 0).0);
+        assert_eq!(
+            vec![Color::with_alpha(255, 128, 0, 128)],
+            dynamodb
+                .select_by_color(&Color::with_alpha(255, 128, 0, 128))
                 .await?
-                .len(),
         );
 
+        for body in [
+            r##"{"color": "#ff800080", "format": "jpeg"}"##,
+            r##"{"color": "#ff8000", "r": 255}"##,
+            r#"{"r": 255, "g": 128}"#,
+            r#"{"color": "reddish"}"#,
+            r#"{"r": 1, "g": 2, "b": 3, "format": "ico", "width": 300}"#,
+        ] {
+            let res = post(body).reply(&routes).await;
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "{}", body);
+        }
+        assert_eq!(4, s3.len());
+        assert!(dynamodb
+            .select_by_color(&Color::new(1, 2, 3))
+            .await?
+            .is_empty());
+        let res = warp::test::request()
+            .path("/v1/render?r=1&g=2&b=3&format=ico&h=300")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+        let res = warp::test::request()
+            .path("/v1/render?color=%23ff800080&format=webp")
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("\"ff800080-30x20.webp\"", res.headers()["ETag"]);
+
+        Ok(())
+    }
+
+    //Runs the whole server with the local backends and talks to it over TCP.
+    #[tokio::test]
+    async fn test19() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let dir = tempfile::tempdir().unwrap();
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config_file = dir.path().join("config.json");
+        std::fs::write(
+            &config_file,
+            serde_json::json!({
+                "port": port,
+                "img_width": 30,
+                "img_height": 20,
+                "s3": {"bucket_name": "bucket", "expiration_sec": 30},
+                "rds": {
+                    "host": "localhost",
+                    "port": 3306,
+                    "user": "user",
+                    "password": "password",
+                    "database_name": "test",
+                    "table_name": "colors"
+                },
+                "dynamodb": {"table_name": "colors"},
+                "outbox": {"dir": dir.path().join("outbox")},
+                "backend": "local",
+                "local": {"dir": dir.path().join("local")}
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let config_file = config_file.to_str().unwrap().to_string();
+        tokio::spawn(async move { listen(&config_file).await });
+
+        let body = r#"{"r": 255, "g": 128, "b": 0}"#;
+        let request = format!(
+            "POST /v1/images HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let start = Instant::now();
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) => break stream,
+                Err(e) if start.elapsed() > Duration::from_secs(10) => panic!("{}", e),
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        };
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let body = serde_json::from_str::<serde_json::Value>(body).unwrap();
+        assert_eq!("success", body["status"]);
+        assert!(body["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/local/objects/ff8000-30x20.png"));
+        assert!(dir.path().join("local/objects/ff8000-30x20.png").exists());
+        assert_eq!(
+            1,
+            std::fs::read_to_string(dir.path().join("local/rds.csv"))
+                .unwrap()
+                .lines()
+                .count()
+        );
+        assert_eq!(
+            1,
+            std::fs::read_to_string(dir.path().join("local/dynamodb.jsonl"))
+                .unwrap()
+                .lines()
+                .count()
+        );
+
+        Ok(())
+    }
+
+    //Only the keys `object_key()` returns are looked up, and rejections don't leak their details.
+    #[tokio::test]
+    async fn test20() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let (_sender, receiver) = watch::channel(state(config, s3.clone(), rds, dynamodb));
+        let routes = routes(receiver);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/v1/images")
+            .header("Content-Type", "application/json")
+            .body(r#"{"r": 255, "g": 128, "b": 0}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(StatusCode::OK, res.status());
+
+        for path in [
+            "/v1/images/FF8000-30x20.png",
+            "/v1/images/ff8000-30x20-q90.png",
+            "/v1/images/ff8000-030x20.png",
+            "/v1/images/config.json",
+        ] {
+            let res = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(StatusCode::NOT_FOUND, res.status(), "{}", path);
+            let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+            assert_eq!("not_found", body["code"], "{}", path);
+        }
+
+        #[derive(Debug)]
+        struct Internal;
+        impl reject::Reject for Internal {}
+        let res = rejection_handler(reject::custom(Internal))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        assert!(!res.body().contains("Internal"));
+        assert!(res
+            .body()
+            .contains("invalid request: the request was rejected"));
+
+        Ok(())
+    }
+
+    //Times which can't be turned into dates are rejected instead of reaching DynamoDB.
+    #[tokio::test]
+    async fn test21() -> Result<()> {
+        let (config, s3, rds, dynamodb) = f();
+        let (_sender, receiver) = watch::channel(state(config, s3, rds, dynamodb));
+        let routes = routes(receiver);
+
+        for (path, status) in [
+            ("/v1/colors?from=299999999999999&to=300000000000000", 400),
+            ("/v1/colors?to=18446744073709551615", 400),
+            ("/v1/colors?from=253402300800001", 400),
+            ("/v1/colors?to=253402300800000", 200),
+        ] {
+            let res = warp::test::request().path(path).reply(&routes).await;
+            assert_eq!(status, res.status().as_u16(), "{}", path);
+        }
+
         Ok(())
     }
 }
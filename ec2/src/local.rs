@@ -0,0 +1,321 @@
+//Local-filesystem stand-ins for S3, RDS and DynamoDB.
+//Objects are written as files under a directory, and each log is a single append-only file.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use super::backend::{KeyValueLog, ObjectStorage, RelationalLog};
+use super::color::Color;
+use super::error::{BoxError, Error, Result};
+
+fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+//The line is written with a single `write` so that concurrent appends are not interleaved.
+async fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(format!("{}\n", line).as_bytes()).await?;
+    file.flush().await
+}
+
+async fn read_lines(path: &Path) -> io::Result<Vec<String>> {
+    let s = match fs::read_to_string(path).await {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    Ok(s.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| l.to_string())
+        .collect())
+}
+
+/*-------------------------------------*/
+
+pub struct LocalObjectStorage {
+    root: PathBuf,
+}
+
+impl LocalObjectStorage {
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        let canonical = match fs::create_dir_all(&root).await {
+            Ok(()) => fs::canonicalize(&root).await,
+            Err(e) => Err(e),
+        };
+        let root = canonical
+            .map_err(|e| Error::storage(format!("failed to create {}", root.display()), e))?;
+        Ok(Self { root })
+    }
+
+    fn path_of(&self, filename: &str) -> Result<PathBuf> {
+        if filename.is_empty() || filename.contains('/') || filename.contains("..") {
+            return Err(Error::storage(
+                "invalid object name",
+                format!("`{}` is not a plain file name", filename),
+            ));
+        }
+        Ok(self.root.join(filename))
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for LocalObjectStorage {
+    async fn upload(&self, filename: &str, image: Bytes, _content_type: &str) -> Result<()> {
+        let path = self.path_of(filename)?;
+        fs::write(path, image)
+            .await
+            .map_err(|e| Error::storage("upload failed", e))
+    }
+
+    async fn exists(&self, filename: &str) -> Result<bool> {
+        let path = self.path_of(filename)?;
+        match fs::metadata(path).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::storage("HEAD failed", e)),
+        }
+    }
+
+    async fn get_presigned_url(&self, filename: &str, _expiration_secs: u32) -> Result<String> {
+        let path = self.path_of(filename)?;
+        if !self.exists(filename).await? {
+            return Err(Error::storage(
+                "failed to create a presigned URL",
+                format!("no such object: {}", filename),
+            ));
+        }
+        Ok(format!("file://{}", path.display()))
+    }
+
+    async fn delete(&self, filename: &str) -> Result<()> {
+        let path = self.path_of(filename)?;
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(Error::storage("delete failed", e))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/*-------------------------------------*/
+
+//Each row is a line of the form `r,g,b,inserted_at`, where `inserted_at` is epoch millis.
+pub struct LocalRelationalLog {
+    path: PathBuf,
+}
+
+impl LocalRelationalLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl RelationalLog for LocalRelationalLog {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        append_line(
+            &self.path,
+            &format!("{},{},{},{}", color.r, color.g, color.b, now_millis()),
+        )
+        .await
+        .map_err(|e| Error::rds("insert failed", e))
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        let parse = |line: &str| -> std::result::Result<Color, BoxError> {
+            let fields = line.split(',').collect::<Vec<_>>();
+            if fields.len() != 4 {
+                return Err(format!("malformed row: {}", line).into());
+            }
+            Ok(Color::new(
+                fields[0].parse()?,
+                fields[1].parse()?,
+                fields[2].parse()?,
+            ))
+        };
+        let mut ret = vec![];
+        for line in read_lines(&self.path)
+            .await
+            .map_err(|e| Error::rds("select failed", e))?
+        {
+            let c = parse(&line).map_err(|e| Error::rds("select failed", e))?;
+            if c == color.to_opaque() {
+                ret.push(c);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/*-------------------------------------*/
+
+#[derive(Deserialize, Serialize)]
+struct Item {
+    color: String,
+    id: String,
+    created_at: u64,
+    r: u8,
+    g: u8,
+    b: u8,
+    //Omitted when opaque, as in DynamoDB.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<u8>,
+}
+
+impl Item {
+    fn color(&self) -> Color {
+        Color::with_alpha(self.r, self.g, self.b, self.a.unwrap_or(u8::MAX))
+    }
+}
+
+//Each item is a line of JSON with the same attributes as the DynamoDB item, except `day`.
+pub struct LocalKeyValueLog {
+    path: PathBuf,
+}
+
+impl LocalKeyValueLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    async fn items(&self) -> Result<Vec<Item>> {
+        read_lines(&self.path)
+            .await
+            .map_err(|e| Error::dynamodb("select failed", e))?
+            .iter()
+            .map(|line| serde_json::from_str(line).map_err(|e| Error::dynamodb("select failed", e)))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl KeyValueLog for LocalKeyValueLog {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        let item = Item {
+            color: color.to_hex(),
+            id: unique_id::generate(),
+            created_at: now_millis() as u64,
+            r: color.r,
+            g: color.g,
+            b: color.b,
+            a: (!color.is_opaque()).then_some(color.a),
+        };
+        append_line(&self.path, &serde_json::to_string(&item).unwrap())
+            .await
+            .map_err(|e| Error::dynamodb("insert failed", e))
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        Ok(self
+            .items()
+            .await?
+            .iter()
+            .filter(|item| item.color == color.to_hex())
+            .map(Item::color)
+            .collect())
+    }
+
+    async fn select_by_time_range(&self, from: SystemTime, to: SystemTime) -> Result<Vec<Color>> {
+        let millis = |t: SystemTime| {
+            t.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        };
+        Ok(self
+            .items()
+            .await?
+            .iter()
+            .filter(|item| (millis(from)..millis(to)).contains(&item.created_at))
+            .map(Item::color)
+            .collect())
+    }
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test01() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+
+        let storage = LocalObjectStorage::new(dir.path().join("objects")).await?;
+        storage
+            .upload("a.png", Bytes::from_static(b"abc"), "image/png")
+            .await?;
+        assert_eq!(
+            b"abc".to_vec(),
+            std::fs::read(dir.path().join("objects").join("a.png"))?
+        );
+        assert!(storage
+            .get_presigned_url("a.png", 30)
+            .await?
+            .starts_with("file://"));
+        assert!(storage.get_presigned_url("b.png", 30).await.is_err());
+        assert!(storage
+            .upload("../a.png", Bytes::new(), "image/png")
+            .await
+            .is_err());
+        storage.delete("a.png").await?;
+        storage.delete("a.png").await?;
+        assert!(storage.get_presigned_url("a.png", 30).await.is_err());
+
+        let color = Color::new(100, 50, 25);
+
+        let rds = LocalRelationalLog::new(dir.path().join("rds.csv"));
+        assert_eq!(0, rds.select_by_color(&color).await?.len());
+        rds.insert(&color).await?;
+        rds.insert(&Color::new(1, 2, 3)).await?;
+        assert_eq!(vec![color.clone()], rds.select_by_color(&color).await?);
+        //Alpha is not recorded.
+        assert_eq!(
+            vec![color.clone()],
+            rds.select_by_color(&Color::with_alpha(100, 50, 25, 0))
+                .await?
+        );
+
+        let dynamodb = LocalKeyValueLog::new(dir.path().join("dynamodb.jsonl"));
+        assert_eq!(0, dynamodb.select_by_color(&color).await?.len());
+        let start = SystemTime::now();
+        dynamodb.insert(&color).await?;
+        dynamodb.insert(&color).await?;
+        dynamodb.insert(&Color::new(1, 2, 3)).await?;
+        assert_eq!(2, dynamodb.select_by_color(&color).await?.len());
+        let end = SystemTime::now() + std::time::Duration::from_millis(1);
+        assert_eq!(
+            vec![color.clone(), color.clone(), Color::new(1, 2, 3)],
+            dynamodb.select_by_time_range(start, end).await?
+        );
+        assert!(dynamodb.select_by_time_range(end, end).await?.is_empty());
+        let translucent = Color::with_alpha(100, 50, 25, 128);
+        dynamodb.insert(&translucent).await?;
+        assert_eq!(2, dynamodb.select_by_color(&color).await?.len());
+        assert_eq!(
+            vec![translucent.clone()],
+            dynamodb.select_by_color(&translucent).await?
+        );
+
+        Ok(())
+    }
+}
+
+/*-------------------------------------*/
@@ -1,15 +1,22 @@
-use std::{error::Error, sync::Arc};
+use std::error::Error;
 
-use ec2::config::Config;
+use ec2::config::CONFIG_FILE_ENV;
 
-const CONFIG_FILE: &str = "./config.json";
+const DEFAULT_CONFIG_FILE: &str = "./config.json";
+
+//The path of the config file is taken from the first argument, `EC2_CONFIG_FILE` or `DEFAULT_CONFIG_FILE` in this order.
+fn config_file() -> String {
+    std::env::args()
+        .nth(1)
+        .or_else(|| std::env::var(CONFIG_FILE_ENV).ok())
+        .unwrap_or_else(|| DEFAULT_CONFIG_FILE.to_string())
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     std::env::set_var("RUST_LOG", "info");
     env_logger::init();
 
-    let config = Arc::new(Config::new(CONFIG_FILE));
-
-    ec2::listen(&config).await
+    ec2::listen(&config_file()).await?;
+    Ok(())
 }
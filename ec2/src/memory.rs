@@ -0,0 +1,238 @@
+//In-memory stand-ins for S3, RDS and DynamoDB, and an in-memory idempotency key store.
+//Nothing is persisted; they are meant for tests and for running the server offline.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use super::backend::{
+    IdempotencyStore, KeyValueLog, ObjectStorage, RelationalLog, Reservation, StoredResponse,
+};
+use super::color::Color;
+use super::error::{Error, Result};
+
+/*-------------------------------------*/
+
+#[derive(Default)]
+pub struct MemoryObjectStorage {
+    objects: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MemoryObjectStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, filename: &str) -> Option<Bytes> {
+        self.objects.lock().unwrap().get(filename).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl ObjectStorage for MemoryObjectStorage {
+    async fn upload(&self, filename: &str, image: Bytes, _content_type: &str) -> Result<()> {
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(filename.to_string(), image);
+        Ok(())
+    }
+
+    async fn exists(&self, filename: &str) -> Result<bool> {
+        Ok(self.objects.lock().unwrap().contains_key(filename))
+    }
+
+    async fn get_presigned_url(&self, filename: &str, expiration_secs: u32) -> Result<String> {
+        if !self.objects.lock().unwrap().contains_key(filename) {
+            return Err(Error::storage(
+                "failed to create a presigned URL",
+                format!("no such object: {}", filename),
+            ));
+        }
+        Ok(format!(
+            "memory://{}?expires_in={}",
+            filename, expiration_secs
+        ))
+    }
+
+    async fn delete(&self, filename: &str) -> Result<()> {
+        self.objects.lock().unwrap().remove(filename);
+        Ok(())
+    }
+}
+
+/*-------------------------------------*/
+
+#[derive(Default)]
+pub struct MemoryRelationalLog {
+    rows: Mutex<Vec<Color>>,
+}
+
+impl MemoryRelationalLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RelationalLog for MemoryRelationalLog {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        self.rows.lock().unwrap().push(color.to_opaque());
+        Ok(())
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        Ok(self
+            .rows
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| **c == color.to_opaque())
+            .cloned()
+            .collect())
+    }
+}
+
+/*-------------------------------------*/
+
+#[derive(Default)]
+pub struct MemoryKeyValueLog {
+    //In the order of insertion, with the time of it.
+    items: Mutex<Vec<(SystemTime, Color)>>,
+}
+
+impl MemoryKeyValueLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl KeyValueLog for MemoryKeyValueLog {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        self.items
+            .lock()
+            .unwrap()
+            .push((SystemTime::now(), color.clone()));
+        Ok(())
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        Ok(self
+            .items
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, c)| c == color)
+            .map(|(_, c)| c.clone())
+            .collect())
+    }
+
+    async fn select_by_time_range(&self, from: SystemTime, to: SystemTime) -> Result<Vec<Color>> {
+        Ok(self
+            .items
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(t, _)| (from..to).contains(t))
+            .map(|(_, c)| c.clone())
+            .collect())
+    }
+}
+
+/*-------------------------------------*/
+
+struct IdempotencyRecord {
+    fingerprint: String,
+    //`None` while the request is in progress.
+    response: Option<StoredResponse>,
+    expires_at: Instant,
+}
+
+//Keys are not shared between processes, which is enough for a single server.
+#[derive(Default)]
+pub struct MemoryIdempotencyStore {
+    records: Mutex<HashMap<String, IdempotencyRecord>>,
+}
+
+impl MemoryIdempotencyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+//Fails if `window_sec` is too long to be represented, which `Config::validate()` doesn't allow.
+fn expires_at(now: Instant, window_sec: u64) -> Result<Instant> {
+    now.checked_add(Duration::from_secs(window_sec))
+        .ok_or_else(|| {
+            Error::config(
+                "invalid idempotency window",
+                format!("{} seconds is too long", window_sec),
+            )
+        })
+}
+
+#[async_trait]
+impl IdempotencyStore for MemoryIdempotencyStore {
+    async fn reserve(&self, key: &str, fingerprint: &str, window_sec: u64) -> Result<Reservation> {
+        let now = Instant::now();
+        let expires_at = expires_at(now, window_sec)?;
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, r| r.expires_at > now);
+        if let Some(record) = records.get(key) {
+            return Ok(match &record.response {
+                None => Reservation::InProgress,
+                Some(response) => Reservation::Done {
+                    fingerprint: record.fingerprint.clone(),
+                    response: response.clone(),
+                },
+            });
+        }
+        records.insert(
+            key.to_string(),
+            IdempotencyRecord {
+                fingerprint: fingerprint.to_string(),
+                response: None,
+                expires_at,
+            },
+        );
+        Ok(Reservation::New)
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        response: &StoredResponse,
+        window_sec: u64,
+    ) -> Result<()> {
+        let expires_at = expires_at(Instant::now(), window_sec)?;
+        self.records.lock().unwrap().insert(
+            key.to_string(),
+            IdempotencyRecord {
+                fingerprint: fingerprint.to_string(),
+                response: Some(response.clone()),
+                expires_at,
+            },
+        );
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<()> {
+        self.records.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/*-------------------------------------*/
@@ -1,104 +1,191 @@
 //ref: |https://docs.rs/mysql/latest/mysql/#example|
 
-use std::error::Error;
+use std::sync::Arc;
 
+use async_trait::async_trait;
 use mysql::{params, prelude::Queryable, OptsBuilder, Pool, PooledConn};
-#[cfg(test)]
-use s3::creds::time::PrimitiveDateTime;
 
+use super::backend::RelationalLog;
+use super::blocking::BlockingPool;
 use super::color::Color;
 use super::config::RDSConfig;
+use super::error::{Error, Result};
 
 /*-------------------------------------*/
 
+//The `mysql` crate is synchronous, so every operation runs on `BlockingPool`.
 pub struct MySQL {
-    connection: PooledConn,
+    inner: Arc<Inner>,
+    blocking: BlockingPool,
+}
+
+struct Inner {
+    pool: Pool,
+    pool_timeout_ms: u32,
     table_name: String,
 }
 
-impl MySQL {
-    pub fn new(config: &RDSConfig) -> Result<Self, Box<dyn Error>> {
+//Whether the connection used for the operation is likely to be broken.
+fn is_connection_error(e: &mysql::Error) -> bool {
+    matches!(
+        e,
+        mysql::Error::IoError(_) | mysql::Error::CodecError(_) | mysql::Error::DriverError(_)
+    )
+}
+
+//Deadlocks and lock wait timeouts succeed when the transaction is simply run again.
+const ER_LOCK_WAIT_TIMEOUT: u16 = 1205;
+const ER_LOCK_DEADLOCK: u16 = 1213;
+
+//Whether the server has rolled the statement back, so that it surely has had no effect.
+fn is_rolled_back(e: &mysql::Error) -> bool {
+    matches!(e, mysql::Error::MySqlError(e) if [ER_LOCK_WAIT_TIMEOUT, ER_LOCK_DEADLOCK].contains(&e.code))
+}
+
+fn is_retryable(e: &mysql::Error) -> bool {
+    is_rolled_back(e) || is_connection_error(e)
+}
+
+fn rds_error(message: &str, e: mysql::Error) -> Error {
+    let retryable = is_retryable(&e);
+    Error::rds(message, e).retryable(retryable)
+}
+
+impl Inner {
+    fn new(config: &RDSConfig) -> Result<Self> {
         let opts = OptsBuilder::new()
             .user(Some(config.user.to_string()))
-            .pass(Some(config.password.to_string()))
+            .pass(Some(config.password.expose()))
             .ip_or_hostname(Some(config.host.to_string()))
             .tcp_port(config.port)
             .db_name(Some(config.database_name.to_string()));
-        let pool = Pool::new(opts)?;
-        let connection = pool.get_conn()?;
+        let pool = Pool::new_manual(config.pool_min, config.pool_max, opts)
+            .map_err(|e| Error::rds("failed to connect", e))?;
 
-        let mut ret = Self {
-            connection,
+        let ret = Self {
+            pool,
+            pool_timeout_ms: config.pool_timeout_ms,
             table_name: config.table_name.to_string(),
         };
         ret.init()?;
         Ok(ret)
     }
 
-    fn init(&mut self) -> Result<(), Box<dyn Error>> {
-        let res = self.connection.query_drop(format!(
-            r"CREATE TABLE IF NOT EXISTS {} (
-                r           int       not null,
-                g           int       not null,
-                b           int       not null,
-                inserted_at timestamp not null default current_timestamp
-            )",
-            &self.table_name
-        ));
-        if let Err(e) = res {
-            Err(e.to_string().into())
-        } else {
-            Ok(())
-        }
+    //Runs `f` with a connection checked out of the pool, which pings it and reconnects if it is dead.
+    //Retrying is left to `retry::Retrying` alone, which the returned error tells whether it may retry.
+    //Once a statement has been sent, a broken connection leaves it unknown whether the statement has been applied,
+    //so a statement which is not `idempotent` is retried only when the server has surely rolled it back.
+    fn with_connection<T>(
+        &self,
+        message: &str,
+        idempotent: bool,
+        f: impl FnOnce(&mut PooledConn) -> mysql::Result<T>,
+    ) -> Result<T> {
+        let mut connection = self
+            .pool
+            .try_get_conn(self.pool_timeout_ms)
+            .map_err(|e| rds_error(message, e))?;
+        f(&mut connection).map_err(|e| {
+            let retryable = if idempotent {
+                is_retryable(&e)
+            } else {
+                is_rolled_back(&e)
+            };
+            Error::rds(message, e).retryable(retryable)
+        })
     }
 
-    pub fn insert(&mut self, c: &Color) -> Result<(), Box<dyn Error>> {
-        let res = self.connection.exec_drop(
-            format!(
-                r"INSERT INTO {} (r, g, b) VALUES (:r, :g, :b)",
+    fn init(&self) -> Result<()> {
+        self.with_connection("failed to create the table", true, |connection| {
+            connection.query_drop(format!(
+                r"CREATE TABLE IF NOT EXISTS {} (
+                    r           int       not null,
+                    g           int       not null,
+                    b           int       not null,
+                    inserted_at timestamp not null default current_timestamp
+                )",
                 &self.table_name
-            ),
-            params! {
-                "r" => c.r,
-                "g" => c.g,
-                "b" => c.b,
-            },
-        );
-        if let Err(e) = res {
-            Err(e.to_string().into())
-        } else {
-            Ok(())
-        }
+            ))
+        })
     }
 
     #[cfg(test)]
-    fn select(&mut self) -> Result<Vec<Color>, Box<dyn Error>> {
-        let res = self.connection.query_map(
-            format!("SELECT * from {}", &self.table_name),
-            |(r, g, b, _): (u8, u8, u8, PrimitiveDateTime)| Color::new(r, g, b),
-        );
-        if let Err(e) = res {
-            Err(e.to_string().into())
-        } else {
-            Ok(res.unwrap())
-        }
+    fn select(&self) -> Result<Vec<Color>> {
+        self.with_connection("select failed", true, |connection| {
+            connection.query_map(
+                format!("SELECT r, g, b from {}", &self.table_name),
+                |(r, g, b): (u8, u8, u8)| Color::new(r, g, b),
+            )
+        })
+    }
+
+    fn insert(&self, c: &Color) -> Result<()> {
+        self.with_connection("insert failed", false, |connection| {
+            connection.exec_drop(
+                format!(
+                    r"INSERT INTO {} (r, g, b) VALUES (:r, :g, :b)",
+                    &self.table_name
+                ),
+                params! {
+                    "r" => c.r,
+                    "g" => c.g,
+                    "b" => c.b,
+                },
+            )
+        })
+    }
+
+    fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        self.with_connection("select failed", true, |connection| {
+            connection.exec_map(
+                format!(
+                    "SELECT r, g, b from {} where r = :r and g = :g and b = :b",
+                    &self.table_name
+                ),
+                params! {"r" => color.r, "g" => color.g, "b" => color.b},
+                |(r, g, b): (u8, u8, u8)| Color::new(r, g, b),
+            )
+        })
+    }
+}
+
+impl MySQL {
+    pub async fn new(config: &RDSConfig) -> Result<Self> {
+        //As many operations as connections can run at once.
+        let blocking = BlockingPool::new(config.pool_max);
+        let config = config.clone();
+        let inner = blocking.run(move || Inner::new(&config)).await?;
+        Ok(Self {
+            inner: Arc::new(inner),
+            blocking,
+        })
+    }
+
+    async fn run<T, F>(&self, f: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(&Inner) -> T + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        self.blocking.run(move || f(&inner)).await
     }
 
     #[cfg(test)]
-    pub fn select_by_color(&mut self, color: &Color) -> Result<Vec<Color>, Box<dyn Error>> {
-        let res = self.connection.exec_map(
-            format!(
-                "SELECT * from {} where r = :r and g = :g and b = :b",
-                &self.table_name
-            ),
-            params! {"r" => color.r, "g" => color.g, "b" => color.b},
-            |(r, g, b, _): (u8, u8, u8, PrimitiveDateTime)| Color::new(r, g, b),
-        );
-        if let Err(e) = res {
-            Err(e.to_string().into())
-        } else {
-            Ok(res.unwrap())
-        }
+    async fn select(&self) -> Result<Vec<Color>> {
+        self.run(|inner| inner.select()).await
+    }
+}
+
+#[async_trait]
+impl RelationalLog for MySQL {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        let color = color.clone();
+        self.run(move |inner| inner.insert(&color)).await
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        let color = color.clone();
+        self.run(move |inner| inner.select_by_color(&color)).await
     }
 }
 
@@ -109,34 +196,62 @@ mod tests {
     use super::super::config::Config;
     use super::*;
 
-    #[test]
-    fn test01() -> Result<(), Box<dyn Error>> {
-        let config = Config::new("./config.json");
+    #[tokio::test]
+    async fn test01() -> Result<()> {
+        //Requires a live RDS instance and `./config.json`.
+        if std::env::var_os("LIVE_TESTS").is_none() {
+            return Ok(());
+        }
+        let config = Config::new("./config.json")?;
 
-        let db = MySQL::new(&config.rds);
+        let db = MySQL::new(&config.rds).await;
         assert!(db.is_ok());
-        let mut db = db.unwrap();
+        let db = db.unwrap();
 
-        let color = Color {
-            r: 100,
-            g: 50,
-            b: 25,
-        };
+        let color = Color::new(100, 50, 25);
 
-        let num_row = db.select_by_color(&color)?.len();
+        let num_row = db.select_by_color(&color).await?.len();
 
-        let res = db.insert(&color);
+        let res = db.insert(&color).await;
         println!("{:?}", res);
         assert!(res.is_ok());
 
-        assert_eq!(num_row + 1, db.select_by_color(&color)?.len());
+        assert_eq!(num_row + 1, db.select_by_color(&color).await?.len());
 
-        let res = db.select();
+        let res = db.select().await;
         println!("{:?}", res);
         assert!(res.is_ok());
 
         Ok(())
     }
+
+    #[test]
+    fn test02() {
+        let server_error = |code| {
+            mysql::Error::MySqlError(mysql::error::MySqlError {
+                state: "40001".to_string(),
+                message: "".to_string(),
+                code,
+            })
+        };
+        assert!(is_retryable(&server_error(ER_LOCK_DEADLOCK)));
+        assert!(is_retryable(&server_error(ER_LOCK_WAIT_TIMEOUT)));
+        //ER_DUP_ENTRY
+        assert!(!is_retryable(&server_error(1062)));
+        assert!(is_retryable(&mysql::Error::DriverError(
+            mysql::DriverError::Timeout
+        )));
+        assert!(rds_error("insert failed", server_error(ER_LOCK_DEADLOCK)).is_retryable());
+
+        //An INSERT which may have been applied is not retried.
+        assert!(is_rolled_back(&server_error(ER_LOCK_DEADLOCK)));
+        assert!(!is_rolled_back(&mysql::Error::DriverError(
+            mysql::DriverError::Timeout
+        )));
+        assert!(!is_rolled_back(&mysql::Error::IoError(
+            std::io::ErrorKind::ConnectionReset.into()
+        )));
+    }
 }
 
 /*-------------------------------------*/
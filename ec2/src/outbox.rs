@@ -0,0 +1,652 @@
+//Every request is recorded here before it is logged to RDS and DynamoDB (the "sinks").
+//The logs are written only after the image has been uploaded, and the entry is discarded if the upload fails.
+//A sink which could not be written to while serving the request is retried in the background
+//until all the sinks have the log, so that they never disagree with each other.
+//Each entry is stored as `<dir>/<id>.json` and removed once it has been delivered to every sink.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use super::color::Color;
+use super::config::OutboxConfig;
+use super::error::{Error, Result};
+use super::state::State;
+
+/*-------------------------------------*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sink {
+    Rds,
+    DynamoDB,
+}
+
+impl Sink {
+    pub const ALL: [Sink; 2] = [Sink::Rds, Sink::DynamoDB];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Rds => "rds",
+            Self::DynamoDB => "dynamodb",
+        }
+    }
+
+    pub async fn write(self, state: &State, color: &Color) -> Result<()> {
+        match self {
+            Self::Rds => state.rds.insert(color).await,
+            Self::DynamoDB => state.dynamodb.insert(color).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Delivered,
+    //Gave up after `max_attempts` attempts. Left for operators to look into.
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Delivery {
+    pub status: Status,
+    pub attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Entry {
+    pub id: String,
+    //Key of the uploaded image.
+    pub filename: String,
+    pub color: Color,
+    //Epoch millis.
+    pub created_at: u64,
+    //Whether the image is known to be in S3. Until then, the logs must not be written.
+    #[serde(default)]
+    pub uploaded: bool,
+    pub deliveries: BTreeMap<Sink, Delivery>,
+}
+
+impl Entry {
+    fn new(id: String, filename: &str, color: &Color) -> Self {
+        let delivery = Delivery {
+            status: Status::Pending,
+            attempts: 0,
+            last_error: None,
+        };
+        Self {
+            id,
+            filename: filename.to_string(),
+            color: color.clone(),
+            created_at: now_millis(),
+            uploaded: false,
+            deliveries: Sink::ALL.iter().map(|&s| (s, delivery.clone())).collect(),
+        }
+    }
+
+    //`Failed` if any sink has failed, otherwise `Pending` if any sink is yet to be written to.
+    pub fn status(&self) -> Status {
+        let statuses = self
+            .deliveries
+            .values()
+            .map(|d| d.status)
+            .collect::<Vec<_>>();
+        if statuses.contains(&Status::Failed) {
+            Status::Failed
+        } else if statuses.contains(&Status::Pending) {
+            Status::Pending
+        } else {
+            Status::Delivered
+        }
+    }
+
+    pub fn pending_sinks(&self) -> Vec<Sink> {
+        self.deliveries
+            .iter()
+            .filter(|(_, d)| d.status == Status::Pending)
+            .map(|(&s, _)| s)
+            .collect()
+    }
+
+    //Records the result of an attempt to write to `sink`.
+    //A write skipped by an open circuit breaker doesn't count as an attempt.
+    pub fn record_attempt(&mut self, sink: Sink, res: &Result<()>, max_attempts: u32) {
+        let delivery = self.deliveries.get_mut(&sink).unwrap();
+        if !matches!(res, Err(Error::CircuitOpen { .. })) {
+            delivery.attempts += 1;
+        }
+        match res {
+            Ok(()) => {
+                delivery.status = Status::Delivered;
+                delivery.last_error = None;
+            }
+            Err(e) => {
+                if delivery.attempts >= max_attempts {
+                    delivery.status = Status::Failed;
+                }
+                delivery.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/*-------------------------------------*/
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+//Writes an entry to `<dir>/<id>.json`, or removes the file if `content` is `None`.
+//The file and the directory are synced, so that the change survives a crash once this returns.
+fn write_entry(dir: &Path, id: &str, content: Option<&str>) -> std::io::Result<()> {
+    let path = dir.join(format!("{}.json", id));
+    match content {
+        Some(content) => {
+            //Written to a temporary file first so that a crash never leaves a truncated entry.
+            let tmp = dir.join(format!("{}.json.tmp", id));
+            let mut file = File::create(&tmp)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)?;
+        }
+        None => match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        },
+    }
+    File::open(dir)?.sync_all()
+}
+
+//An entry being delivered by a request or by the worker, which no one else touches meanwhile.
+//It is handed back with `Outbox::update()` or `Outbox::discard()`.
+//If it is dropped instead (e.g. when the request is cancelled), it is released as last persisted, and the worker takes it over.
+pub struct Claim<'a> {
+    outbox: &'a Outbox,
+    entry: Entry,
+}
+
+impl Deref for Claim<'_> {
+    type Target = Entry;
+
+    fn deref(&self) -> &Entry {
+        &self.entry
+    }
+}
+
+impl DerefMut for Claim<'_> {
+    fn deref_mut(&mut self) -> &mut Entry {
+        &mut self.entry
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.outbox.claimed.lock().unwrap().remove(&self.entry.id);
+    }
+}
+
+pub struct Outbox {
+    //`None` keeps the entries only in memory, which is for tests.
+    dir: Option<PathBuf>,
+    max_attempts: u32,
+    entries: Mutex<BTreeMap<String, Entry>>,
+    //Entries being delivered right now, either by a request or by the worker.
+    claimed: Mutex<HashSet<String>>,
+}
+
+impl Outbox {
+    //Opens the outbox in `config.dir`, loading the entries left by the previous run.
+    pub fn open(config: &OutboxConfig) -> Result<Self> {
+        let dir = PathBuf::from(&config.dir);
+        fs::create_dir_all(&dir)
+            .map_err(|e| Error::outbox(format!("failed to create {}", dir.display()), e))?;
+
+        let mut entries = BTreeMap::new();
+        let read_dir = fs::read_dir(&dir)
+            .map_err(|e| Error::outbox(format!("failed to read {}", dir.display()), e))?;
+        for dir_entry in read_dir {
+            let path = dir_entry
+                .map_err(|e| Error::outbox(format!("failed to read {}", dir.display()), e))?
+                .path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("json") => (),
+                //Left by a write which was interrupted before the rename, so the entry has the previous content or none.
+                Some("tmp") => {
+                    match fs::remove_file(&path) {
+                        Ok(()) => info!("removed the stale {}", path.display()),
+                        Err(e) => warn!("failed to remove {}: {}", path.display(), e),
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+            //A broken entry must not keep the server from starting, so it is moved aside for operators to look into.
+            let s = match fs::read_to_string(&path) {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("failed to read {}, skipping it: {}", path.display(), e);
+                    continue;
+                }
+            };
+            match serde_json::from_str::<Entry>(&s) {
+                Ok(entry) => {
+                    entries.insert(entry.id.clone(), entry);
+                }
+                Err(e) => {
+                    let quarantined = path.with_extension("json.corrupt");
+                    error!(
+                        "failed to parse {}, moving it to {}: {}",
+                        path.display(),
+                        quarantined.display(),
+                        e
+                    );
+                    if let Err(e) = fs::rename(&path, &quarantined) {
+                        warn!("failed to move {}: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+        if !entries.is_empty() {
+            info!(
+                "loaded {} outbox entries from {}",
+                entries.len(),
+                dir.display()
+            );
+        }
+
+        Ok(Self {
+            dir: Some(dir),
+            max_attempts: config.max_attempts,
+            entries: Mutex::new(entries),
+            claimed: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn in_memory(max_attempts: u32) -> Self {
+        Self {
+            dir: None,
+            max_attempts,
+            entries: Mutex::new(BTreeMap::new()),
+            claimed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    //Writes `entry` to its file, or removes the file if `remove` is set.
+    async fn persist(&self, entry: &Entry, remove: bool) -> Result<()> {
+        let Some(dir) = self.dir.clone() else {
+            return Ok(());
+        };
+        let id = entry.id.clone();
+        let content = (!remove).then(|| serde_json::to_string(entry).unwrap());
+        tokio::task::spawn_blocking(move || {
+            write_entry(&dir, &id, content.as_deref()).map_err(|e| {
+                Error::outbox(
+                    format!(
+                        "failed to write {}",
+                        dir.join(format!("{}.json", id)).display()
+                    ),
+                    e,
+                )
+            })
+        })
+        .await
+        .map_err(|e| Error::outbox("failed to write an entry", e))?
+    }
+
+    //Durably records a new request, which is claimed by the caller.
+    pub async fn record(&self, filename: &str, color: &Color) -> Result<Claim<'_>> {
+        let entry = Entry::new(unique_id::generate(), filename, color);
+        self.persist(&entry, false).await?;
+        self.claimed.lock().unwrap().insert(entry.id.clone());
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(entry.id.clone(), entry.clone());
+        Ok(Claim {
+            outbox: self,
+            entry,
+        })
+    }
+
+    //Saves the progress of a claimed entry and releases it.
+    pub async fn update(&self, claim: Claim<'_>) -> Result<()> {
+        let delivered = claim.status() == Status::Delivered;
+        let res = self.persist(&claim, delivered).await;
+        let mut entries = self.entries.lock().unwrap();
+        if delivered {
+            entries.remove(&claim.id);
+        } else {
+            entries.insert(claim.id.clone(), claim.entry.clone());
+        }
+        res
+    }
+
+    //Removes a claimed entry whose logs must not be written, because its image was not uploaded.
+    pub async fn discard(&self, claim: Claim<'_>) -> Result<()> {
+        let res = self.persist(&claim, true).await;
+        self.entries.lock().unwrap().remove(&claim.id);
+        res
+    }
+
+    //Claims the entries which have a sink to retry and are not claimed by anyone else.
+    pub fn claim_pending(&self) -> Vec<Claim<'_>> {
+        let entries = self.entries.lock().unwrap();
+        let mut claimed = self.claimed.lock().unwrap();
+        entries
+            .values()
+            .filter(|e| !e.pending_sinks().is_empty() && claimed.insert(e.id.clone()))
+            .map(|e| Claim {
+                outbox: self,
+                entry: e.clone(),
+            })
+            .collect()
+    }
+
+    //Entries not yet delivered to every sink, oldest first, optionally filtered by `Entry::status()`.
+    pub fn list(&self, status: Option<Status>) -> Vec<Entry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|e| status.is_none_or(|s| e.status() == s))
+            .cloned()
+            .collect()
+    }
+}
+
+/*-------------------------------------*/
+
+//Retries every pending entry once.
+pub async fn retry_pending(state: &State) {
+    let outbox = &state.outbox;
+    for mut entry in outbox.claim_pending() {
+        //The server stopped or the request was cancelled before the upload finished,
+        //so the logs are written only if the image has made it to S3.
+        if !entry.uploaded {
+            match state.s3.exists(&entry.filename).await {
+                Ok(true) => entry.uploaded = true,
+                Ok(false) => {
+                    info!(
+                        "discarding {} as {} was not uploaded",
+                        entry.id, entry.filename
+                    );
+                    if let Err(e) = outbox.discard(entry).await {
+                        error!("{}", e);
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    warn!("failed to check if {} exists: {}", entry.filename, e);
+                    continue;
+                }
+            }
+        }
+        for sink in entry.pending_sinks() {
+            let res = sink.write(state, &entry.color).await;
+            if let Err(e) = &res {
+                warn!("failed to deliver {} to {}: {}", entry.id, sink.name(), e);
+            }
+            entry.record_attempt(sink, &res, outbox.max_attempts());
+        }
+        if entry.status() == Status::Failed {
+            error!("gave up delivering {}", entry.id);
+        }
+        if let Err(e) = outbox.update(entry).await {
+            error!("{}", e);
+        }
+    }
+}
+
+//Runs in the background for the lifetime of the server.
+pub async fn run(state: watch::Receiver<Arc<State>>) {
+    loop {
+        let interval = state.borrow().config.outbox.retry_interval_ms;
+        tokio::time::sleep(Duration::from_millis(interval)).await;
+        let state = state.borrow().clone();
+        retry_pending(&state).await;
+    }
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::backend::KeyValueLog;
+    use crate::config::Config;
+    use crate::memory::{
+        MemoryIdempotencyStore, MemoryKeyValueLog, MemoryObjectStorage, MemoryRelationalLog,
+    };
+
+    //Fails the first `failures` inserts.
+    struct FlakyKeyValueLog {
+        failures: AtomicUsize,
+        inner: MemoryKeyValueLog,
+    }
+
+    #[async_trait]
+    impl KeyValueLog for FlakyKeyValueLog {
+        async fn insert(&self, color: &Color) -> Result<()> {
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return Err(Error::dynamodb("insert failed", "throttled"));
+            }
+            self.inner.insert(color).await
+        }
+
+        async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+            self.inner.select_by_color(color).await
+        }
+
+        async fn select_by_time_range(
+            &self,
+            from: SystemTime,
+            to: SystemTime,
+        ) -> Result<Vec<Color>> {
+            self.inner.select_by_time_range(from, to).await
+        }
+    }
+
+    fn state(outbox: Outbox, failures: usize) -> State {
+        let config: Config = serde_json::from_str(
+            r#"
+                {
+                    "port": 8080,
+                    "img_width": 30,
+                    "img_height": 20,
+                    "s3": {"bucket_name": "bucket", "expiration_sec": 30},
+                    "rds": {
+                        "host": "localhost",
+                        "port": 3306,
+                        "user": "user",
+                        "password": "password",
+                        "database_name": "db",
+                        "table_name": "tbl"
+                    },
+                    "dynamodb": {"table_name": "tbl"}
+                }
+            "#,
+        )
+        .unwrap();
+        State {
+            config: Arc::new(config),
+            s3: Arc::new(MemoryObjectStorage::new()),
+            rds: Arc::new(MemoryRelationalLog::new()),
+            dynamodb: Arc::new(FlakyKeyValueLog {
+                failures: AtomicUsize::new(failures),
+                inner: MemoryKeyValueLog::new(),
+            }),
+            outbox: Arc::new(outbox),
+            idempotency: Arc::new(MemoryIdempotencyStore::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test01() -> Result<()> {
+        let state = state(Outbox::in_memory(3), 2);
+        let color = Color::new(1, 2, 3);
+
+        let mut entry = state.outbox.record("a.png", &color).await?;
+        assert!(state.outbox.claim_pending().is_empty());
+        entry.uploaded = true;
+        state.outbox.update(entry).await?;
+
+        //The first attempt succeeds for RDS but not for DynamoDB.
+        retry_pending(&state).await;
+        let entries = state.outbox.list(Some(Status::Pending));
+        assert_eq!(1, entries.len());
+        assert_eq!(vec![Sink::DynamoDB], entries[0].pending_sinks());
+        assert_eq!(1, state.rds.select_by_color(&color).await?.len());
+
+        //The entry is removed once DynamoDB has it too.
+        retry_pending(&state).await;
+        assert_eq!(1, state.outbox.list(None).len());
+        retry_pending(&state).await;
+        assert!(state.outbox.list(None).is_empty());
+        assert_eq!(1, state.dynamodb.select_by_color(&color).await?.len());
+        assert_eq!(1, state.rds.select_by_color(&color).await?.len());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test02() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let config = OutboxConfig {
+            dir: dir.path().to_str().unwrap().to_string(),
+            max_attempts: 2,
+            ..Default::default()
+        };
+
+        //Entries survive a restart, even one left by a request which was interrupted before the upload finished.
+        let outbox = Outbox::open(&config)?;
+        let entry = outbox.record("a.png", &Color::new(1, 2, 3)).await?;
+        let id = entry.id.clone();
+        drop(entry);
+        drop(outbox);
+        let state = state(Outbox::open(&config)?, 100);
+        let pending = state.outbox.list(Some(Status::Pending));
+        assert_eq!(1, pending.len());
+        assert_eq!(id, pending[0].id);
+        assert!(!pending[0].uploaded);
+        //The upload has actually completed.
+        state
+            .s3
+            .upload("a.png", bytes::Bytes::new(), "image/png")
+            .await?;
+
+        //Failed entries are kept but not retried.
+        retry_pending(&state).await;
+        retry_pending(&state).await;
+        let failed = state.outbox.list(Some(Status::Failed));
+        assert_eq!(1, failed.len());
+        let delivery = &failed[0].deliveries[&Sink::DynamoDB];
+        assert_eq!(2, delivery.attempts);
+        assert_eq!(
+            Some("DynamoDB error: insert failed"),
+            delivery.last_error.as_deref()
+        );
+        assert!(state.outbox.claim_pending().is_empty());
+        assert!(dir.path().join(format!("{}.json", id)).exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test03() -> Result<()> {
+        let state = state(Outbox::in_memory(3), 0);
+        let color = Color::new(1, 2, 3);
+
+        //A claim dropped without being handed back (e.g. by a cancelled request) is released to the worker.
+        let entry = state.outbox.record("a.png", &color).await?;
+        assert!(state.outbox.claim_pending().is_empty());
+        drop(entry);
+        assert_eq!(1, state.outbox.claim_pending().len());
+
+        //As its image was never uploaded, it is discarded without writing the logs.
+        retry_pending(&state).await;
+        assert!(state.outbox.list(None).is_empty());
+        assert!(state.rds.select_by_color(&color).await?.is_empty());
+        assert!(state.dynamodb.select_by_color(&color).await?.is_empty());
+
+        //A discarded entry leaves no file behind.
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(&OutboxConfig {
+            dir: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })?;
+        let entry = outbox.record("a.png", &color).await?;
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+        outbox.discard(entry).await?;
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+        assert!(outbox.claim_pending().is_empty());
+
+        Ok(())
+    }
+
+    //Broken files left in the directory don't keep the outbox from opening.
+    #[tokio::test]
+    async fn test04() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let config = OutboxConfig {
+            dir: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let outbox = Outbox::open(&config)?;
+        let entry = outbox.record("a.png", &Color::new(1, 2, 3)).await?;
+        let id = entry.id.clone();
+        drop(entry);
+
+        fs::write(dir.path().join("b.json"), r#"{"id": "b", "filenam"#).unwrap();
+        fs::write(dir.path().join("c.json.tmp"), r#"{"id": "c", "#).unwrap();
+
+        let outbox = Outbox::open(&config)?;
+        assert_eq!(
+            vec![id.clone()],
+            outbox
+                .list(None)
+                .into_iter()
+                .map(|e| e.id)
+                .collect::<Vec<_>>()
+        );
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            vec![format!("{}.json", id), "b.json.corrupt".to_string()],
+            files
+        );
+
+        Ok(())
+    }
+}
+
+/*-------------------------------------*/
@@ -0,0 +1,206 @@
+//Reloads the config file on SIGHUP or when the file is modified.
+
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use super::config::Config;
+use super::error::Result;
+use super::state::{BackendBuilder, State};
+
+//How often the modification time of the config file is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct Reloader<B> {
+    config_file: String,
+    builder: B,
+    sender: watch::Sender<Arc<State>>,
+    modified: Option<SystemTime>,
+}
+
+impl<B: BackendBuilder> Reloader<B> {
+    pub fn new(config_file: &str, builder: B, sender: watch::Sender<Arc<State>>) -> Self {
+        let mut ret = Self {
+            config_file: config_file.to_string(),
+            builder,
+            sender,
+            modified: None,
+        };
+        ret.modified = ret.modified_time();
+        ret
+    }
+
+    fn modified_time(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.config_file)
+            .and_then(|m| m.modified())
+            .ok()
+    }
+
+    //Reads the config file again and publishes a new `State` if anything has changed.
+    //On error, the current `State` is kept as is.
+    pub async fn reload(&self) -> Result<()> {
+        let config = Config::new(&self.config_file)?;
+        config.validate()?;
+
+        let current = self.sender.borrow().clone();
+        if config == *current.config {
+            info!("{} is unchanged", self.config_file);
+            return Ok(());
+        }
+        if config.port != current.config.port {
+            warn!("`port` is changed but it takes effect only after restart");
+        }
+        if config.outbox != current.config.outbox {
+            warn!("`outbox` is changed but it takes effect only after restart");
+        }
+        if config.backend != current.config.backend || config.local != current.config.local {
+            warn!("`backend` or `local` is changed but it takes effect only after restart");
+        }
+
+        let state = current.rebuild(config, &self.builder).await?;
+        self.sender.send_replace(Arc::new(state));
+        info!("reloaded {}", self.config_file);
+        Ok(())
+    }
+
+    pub async fn run(mut self) {
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to listen to SIGHUP");
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    info!("received SIGHUP");
+                }
+                _ = interval.tick() => {
+                    let modified = self.modified_time();
+                    if modified == self.modified {
+                        continue;
+                    }
+                    self.modified = modified;
+                    info!("{} is modified", self.config_file);
+                }
+            }
+            if let Err(e) = self.reload().await {
+                error!("failed to reload {}: {}", self.config_file, e);
+            }
+        }
+    }
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::backend::{IdempotencyStore, KeyValueLog, ObjectStorage, RelationalLog};
+    use crate::config::{DynamoDBConfig, IdempotencyConfig, OutboxConfig, RDSConfig, S3Config};
+    use crate::memory::{
+        MemoryIdempotencyStore, MemoryKeyValueLog, MemoryObjectStorage, MemoryRelationalLog,
+    };
+    use crate::outbox::Outbox;
+
+    #[derive(Default)]
+    struct CountingBackendBuilder {
+        num_built: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl BackendBuilder for CountingBackendBuilder {
+        async fn s3(&self, _: &S3Config) -> Result<Arc<dyn ObjectStorage>> {
+            self.num_built.fetch_add(1, Ordering::SeqCst);
+            Ok(Arc::new(MemoryObjectStorage::new()))
+        }
+
+        async fn rds(&self, _: &RDSConfig) -> Result<Arc<dyn RelationalLog>> {
+            self.num_built.fetch_add(1, Ordering::SeqCst);
+            Ok(Arc::new(MemoryRelationalLog::new()))
+        }
+
+        async fn dynamodb(&self, _: &DynamoDBConfig) -> Result<Arc<dyn KeyValueLog>> {
+            self.num_built.fetch_add(1, Ordering::SeqCst);
+            Ok(Arc::new(MemoryKeyValueLog::new()))
+        }
+
+        fn outbox(&self, config: &OutboxConfig) -> Result<Arc<Outbox>> {
+            Ok(Arc::new(Outbox::in_memory(config.max_attempts)))
+        }
+
+        async fn idempotency(&self, _: &IdempotencyConfig) -> Result<Arc<dyn IdempotencyStore>> {
+            Ok(Arc::new(MemoryIdempotencyStore::new()))
+        }
+    }
+
+    fn config_json(img_width: u32, rds_table_name: &str) -> String {
+        format!(
+            r#"{{
+                "port": 30021,
+                "img_width": {},
+                "img_height": 20,
+                "s3": {{"bucket_name": "bucket", "expiration_sec": 30}},
+                "rds": {{
+                    "host": "localhost",
+                    "port": 3306,
+                    "user": "user",
+                    "password": "password",
+                    "database_name": "test",
+                    "table_name": "{}"
+                }},
+                "dynamodb": {{"table_name": "colors"}}
+            }}"#,
+            img_width, rds_table_name
+        )
+    }
+
+    #[tokio::test]
+    async fn test01() -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, config_json(30, "colors")).unwrap();
+
+        let builder = CountingBackendBuilder::default();
+        let state = State::build(Config::new(path)?, &builder).await?;
+        assert_eq!(3, builder.num_built.load(Ordering::SeqCst));
+        let (sender, receiver) = watch::channel(Arc::new(state));
+        let reloader = Reloader::new(path, builder, sender);
+
+        let old = receiver.borrow().clone();
+
+        //Only the request-level settings are changed, so no backend is rebuilt.
+        std::fs::write(path, config_json(40, "colors")).unwrap();
+        reloader.reload().await?;
+        let new = receiver.borrow().clone();
+        assert_eq!(40, new.config.img_width);
+        assert_eq!(3, reloader.builder.num_built.load(Ordering::SeqCst));
+        assert!(Arc::ptr_eq(&old.s3, &new.s3));
+        assert!(Arc::ptr_eq(&old.rds, &new.rds));
+
+        //A request which started before the reload keeps the old config.
+        assert_eq!(30, old.config.img_width);
+
+        //Only RDS is rebuilt.
+        std::fs::write(path, config_json(40, "colors_2")).unwrap();
+        reloader.reload().await?;
+        let newer = receiver.borrow().clone();
+        assert_eq!(4, reloader.builder.num_built.load(Ordering::SeqCst));
+        assert!(Arc::ptr_eq(&new.s3, &newer.s3));
+        assert!(!Arc::ptr_eq(&new.rds, &newer.rds));
+        assert!(Arc::ptr_eq(&new.dynamodb, &newer.dynamodb));
+
+        //An invalid config is rejected and the current state is kept.
+        std::fs::write(path, config_json(0, "colors_3")).unwrap();
+        assert!(reloader.reload().await.is_err());
+        assert!(Arc::ptr_eq(&newer, &receiver.borrow()));
+
+        Ok(())
+    }
+}
+
+/*-------------------------------------*/
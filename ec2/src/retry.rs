@@ -0,0 +1,271 @@
+//Retries transient failures of the backends with exponential backoff and jitter.
+//Each backend decides which of its errors are transient (see `Error::retryable()`),
+//and `Retrying` wraps it to retry those according to its section's `RetryConfig`.
+
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use aws_smithy_http::result::SdkError;
+use aws_smithy_types::retry::ProvideErrorKind;
+use bytes::Bytes;
+use log::warn;
+
+use super::backend::{KeyValueLog, ObjectStorage, RelationalLog};
+use super::color::Color;
+use super::config::RetryConfig;
+use super::error::Result;
+
+/*-------------------------------------*/
+
+//Whether an error of the AWS SDK is worth retrying.
+//Besides the errors modeled as retryable, `codes` lists the service-specific error codes to retry.
+pub fn is_retryable_sdk_error<E: ProvideErrorKind>(e: &SdkError<E>, codes: &[&str]) -> bool {
+    match e {
+        SdkError::ConstructionFailure(_) => false,
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(context) => {
+            let status = context.raw().http().status();
+            let err = context.err();
+            status.is_server_error()
+                || status.as_u16() == 429
+                || err.retryable_error_kind().is_some()
+                || err.code().is_some_and(|code| codes.contains(&code))
+        }
+        _ => false,
+    }
+}
+
+/*-------------------------------------*/
+
+pub struct Retrying<B> {
+    inner: B,
+    config: RetryConfig,
+}
+
+impl<B> Retrying<B> {
+    pub fn new(inner: B, config: &RetryConfig) -> Self {
+        Self {
+            inner,
+            config: config.clone(),
+        }
+    }
+
+    //How long to wait before the `n`-th retry (1-origin).
+    fn delay(&self, n: u32) -> Duration {
+        let delay = self
+            .config
+            .base_delay_ms
+            .saturating_mul(2u64.saturating_pow(n - 1))
+            .min(self.config.max_delay_ms);
+        let delay = if self.config.jitter {
+            fastrand::u64(0..=delay)
+        } else {
+            delay
+        };
+        Duration::from_millis(delay)
+    }
+
+    async fn run<T, F, Fut>(&self, operation: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T>> + Send,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if e.is_retryable() && attempt < self.config.max_attempts => {
+                    let delay = self.delay(attempt);
+                    warn!(
+                        "{} failed (attempt {} of {}), retrying in {:?}: {}",
+                        operation, attempt, self.config.max_attempts, delay, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<B: ObjectStorage> ObjectStorage for Retrying<B> {
+    async fn upload(&self, filename: &str, image: Bytes, content_type: &str) -> Result<()> {
+        self.run("upload", || {
+            self.inner.upload(filename, image.clone(), content_type)
+        })
+        .await
+    }
+
+    async fn exists(&self, filename: &str) -> Result<bool> {
+        self.run("HEAD", || self.inner.exists(filename)).await
+    }
+
+    async fn get_presigned_url(&self, filename: &str, expiration_secs: u32) -> Result<String> {
+        self.run("presign", || {
+            self.inner.get_presigned_url(filename, expiration_secs)
+        })
+        .await
+    }
+
+    async fn delete(&self, filename: &str) -> Result<()> {
+        self.run("delete", || self.inner.delete(filename)).await
+    }
+}
+
+#[async_trait]
+impl<B: RelationalLog> RelationalLog for Retrying<B> {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        self.run("RDS insert", || self.inner.insert(color)).await
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        self.run("RDS select", || self.inner.select_by_color(color))
+            .await
+    }
+}
+
+#[async_trait]
+impl<B: KeyValueLog> KeyValueLog for Retrying<B> {
+    async fn insert(&self, color: &Color) -> Result<()> {
+        self.run("DynamoDB insert", || self.inner.insert(color))
+            .await
+    }
+
+    async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+        self.run("DynamoDB select", || self.inner.select_by_color(color))
+            .await
+    }
+
+    async fn select_by_time_range(&self, from: SystemTime, to: SystemTime) -> Result<Vec<Color>> {
+        self.run("DynamoDB select", || {
+            self.inner.select_by_time_range(from, to)
+        })
+        .await
+    }
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::error::Error;
+    use crate::memory::MemoryKeyValueLog;
+
+    //Fails the first `failures` inserts with `error`.
+    struct FlakyKeyValueLog {
+        failures: usize,
+        error: fn() -> Error,
+        num_attempt: AtomicUsize,
+        inner: MemoryKeyValueLog,
+    }
+
+    impl FlakyKeyValueLog {
+        fn new(failures: usize, error: fn() -> Error) -> Self {
+            Self {
+                failures,
+                error,
+                num_attempt: AtomicUsize::new(0),
+                inner: MemoryKeyValueLog::new(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl KeyValueLog for FlakyKeyValueLog {
+        async fn insert(&self, color: &Color) -> Result<()> {
+            if self.num_attempt.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err((self.error)());
+            }
+            self.inner.insert(color).await
+        }
+
+        async fn select_by_color(&self, color: &Color) -> Result<Vec<Color>> {
+            self.inner.select_by_color(color).await
+        }
+
+        async fn select_by_time_range(
+            &self,
+            from: SystemTime,
+            to: SystemTime,
+        ) -> Result<Vec<Color>> {
+            self.inner.select_by_time_range(from, to).await
+        }
+    }
+
+    fn throttled() -> Error {
+        Error::dynamodb("insert failed", "ProvisionedThroughputExceededException").retryable(true)
+    }
+
+    fn validation_failed() -> Error {
+        Error::dynamodb("insert failed", "ValidationException")
+    }
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 1,
+            max_delay_ms: 10,
+            jitter: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test01() -> Result<()> {
+        let color = Color::new(1, 2, 3);
+
+        //Succeeds at the last attempt.
+        let log = Retrying::new(FlakyKeyValueLog::new(2, throttled), &config());
+        log.insert(&color).await?;
+        assert_eq!(3, log.inner.num_attempt.load(Ordering::SeqCst));
+        assert_eq!(1, log.select_by_color(&color).await?.len());
+
+        //Gives up after `max_attempts` attempts.
+        let log = Retrying::new(FlakyKeyValueLog::new(3, throttled), &config());
+        assert!(log.insert(&color).await.unwrap_err().is_retryable());
+        assert_eq!(3, log.inner.num_attempt.load(Ordering::SeqCst));
+
+        //Doesn't retry a permanent error.
+        let log = Retrying::new(FlakyKeyValueLog::new(1, validation_failed), &config());
+        assert!(log.insert(&color).await.is_err());
+        assert_eq!(1, log.inner.num_attempt.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test02() {
+        let retrying = Retrying::new(
+            (),
+            &RetryConfig {
+                max_attempts: 10,
+                base_delay_ms: 100,
+                max_delay_ms: 1000,
+                jitter: false,
+            },
+        );
+        let delays = (1..=6)
+            .map(|n| retrying.delay(n).as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![100, 200, 400, 800, 1000, 1000], delays);
+
+        let retrying = Retrying::new(
+            (),
+            &RetryConfig {
+                jitter: true,
+                ..retrying.config.clone()
+            },
+        );
+        for n in 1..=6 {
+            assert!(retrying.delay(n) <= Duration::from_millis(delays[n as usize - 1] as u64));
+        }
+    }
+}
+
+/*-------------------------------------*/
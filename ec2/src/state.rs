@@ -0,0 +1,192 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use super::backend::{IdempotencyStore, KeyValueLog, ObjectStorage, RelationalLog};
+use super::breaker::Breaking;
+use super::config::{Config, DynamoDBConfig, IdempotencyConfig, OutboxConfig, RDSConfig, S3Config};
+use super::dynamodb::{DynamoDB, DynamoDBIdempotencyStore};
+use super::error::Result;
+use super::local::{LocalKeyValueLog, LocalObjectStorage, LocalRelationalLog};
+use super::memory::{
+    MemoryIdempotencyStore, MemoryKeyValueLog, MemoryObjectStorage, MemoryRelationalLog,
+};
+use super::mysql::MySQL;
+use super::outbox::Outbox;
+use super::retry::Retrying;
+use super::s3::S3;
+
+//Everything a request needs.
+//A request keeps using the `State` it started with even if the config is reloaded in the meantime.
+pub struct State {
+    pub config: Arc<Config>,
+    pub s3: Arc<dyn ObjectStorage>,
+    pub rds: Arc<dyn RelationalLog>,
+    pub dynamodb: Arc<dyn KeyValueLog>,
+    pub outbox: Arc<Outbox>,
+    pub idempotency: Arc<dyn IdempotencyStore>,
+}
+
+//Creates the backends from their sections of `Config`.
+#[async_trait]
+pub trait BackendBuilder: Send + Sync {
+    async fn s3(&self, config: &S3Config) -> Result<Arc<dyn ObjectStorage>>;
+
+    async fn rds(&self, config: &RDSConfig) -> Result<Arc<dyn RelationalLog>>;
+
+    async fn dynamodb(&self, config: &DynamoDBConfig) -> Result<Arc<dyn KeyValueLog>>;
+
+    fn outbox(&self, config: &OutboxConfig) -> Result<Arc<Outbox>>;
+
+    async fn idempotency(&self, config: &IdempotencyConfig) -> Result<Arc<dyn IdempotencyStore>>;
+}
+
+//Builds `S3`, `MySQL` and `DynamoDB` behind retries and circuit breakers, and opens the outbox on the local disk.
+pub struct AwsBackendBuilder;
+
+#[async_trait]
+impl BackendBuilder for AwsBackendBuilder {
+    async fn s3(&self, config: &S3Config) -> Result<Arc<dyn ObjectStorage>> {
+        Ok(Arc::new(Breaking::new(
+            "s3",
+            Retrying::new(S3::new(config).await?, &config.retry),
+            &config.circuit_breaker,
+        )))
+    }
+
+    async fn rds(&self, config: &RDSConfig) -> Result<Arc<dyn RelationalLog>> {
+        Ok(Arc::new(Breaking::new(
+            "rds",
+            Retrying::new(MySQL::new(config).await?, &config.retry),
+            &config.circuit_breaker,
+        )))
+    }
+
+    async fn dynamodb(&self, config: &DynamoDBConfig) -> Result<Arc<dyn KeyValueLog>> {
+        Ok(Arc::new(Breaking::new(
+            "dynamodb",
+            Retrying::new(DynamoDB::new(config).await?, &config.retry),
+            &config.circuit_breaker,
+        )))
+    }
+
+    fn outbox(&self, config: &OutboxConfig) -> Result<Arc<Outbox>> {
+        Ok(Arc::new(Outbox::open(config)?))
+    }
+
+    async fn idempotency(&self, config: &IdempotencyConfig) -> Result<Arc<dyn IdempotencyStore>> {
+        Ok(match &config.table_name {
+            Some(table_name) => Arc::new(DynamoDBIdempotencyStore::new(table_name).await),
+            None => Arc::new(MemoryIdempotencyStore::new()),
+        })
+    }
+}
+
+//Builds the file-based backends of `local` under `dir`, and opens the outbox on the local disk.
+//Idempotency keys are kept in memory.
+pub struct LocalBackendBuilder {
+    pub dir: PathBuf,
+}
+
+#[async_trait]
+impl BackendBuilder for LocalBackendBuilder {
+    async fn s3(&self, _: &S3Config) -> Result<Arc<dyn ObjectStorage>> {
+        Ok(Arc::new(
+            LocalObjectStorage::new(self.dir.join("objects")).await?,
+        ))
+    }
+
+    async fn rds(&self, _: &RDSConfig) -> Result<Arc<dyn RelationalLog>> {
+        Ok(Arc::new(LocalRelationalLog::new(self.dir.join("rds.csv"))))
+    }
+
+    async fn dynamodb(&self, _: &DynamoDBConfig) -> Result<Arc<dyn KeyValueLog>> {
+        Ok(Arc::new(LocalKeyValueLog::new(
+            self.dir.join("dynamodb.jsonl"),
+        )))
+    }
+
+    fn outbox(&self, config: &OutboxConfig) -> Result<Arc<Outbox>> {
+        Ok(Arc::new(Outbox::open(config)?))
+    }
+
+    async fn idempotency(&self, _: &IdempotencyConfig) -> Result<Arc<dyn IdempotencyStore>> {
+        Ok(Arc::new(MemoryIdempotencyStore::new()))
+    }
+}
+
+//Builds the in-memory backends of `memory`.
+//A backend whose section is changed on reload starts empty.
+pub struct MemoryBackendBuilder;
+
+#[async_trait]
+impl BackendBuilder for MemoryBackendBuilder {
+    async fn s3(&self, _: &S3Config) -> Result<Arc<dyn ObjectStorage>> {
+        Ok(Arc::new(MemoryObjectStorage::new()))
+    }
+
+    async fn rds(&self, _: &RDSConfig) -> Result<Arc<dyn RelationalLog>> {
+        Ok(Arc::new(MemoryRelationalLog::new()))
+    }
+
+    async fn dynamodb(&self, _: &DynamoDBConfig) -> Result<Arc<dyn KeyValueLog>> {
+        Ok(Arc::new(MemoryKeyValueLog::new()))
+    }
+
+    fn outbox(&self, config: &OutboxConfig) -> Result<Arc<Outbox>> {
+        Ok(Arc::new(Outbox::in_memory(config.max_attempts)))
+    }
+
+    async fn idempotency(&self, _: &IdempotencyConfig) -> Result<Arc<dyn IdempotencyStore>> {
+        Ok(Arc::new(MemoryIdempotencyStore::new()))
+    }
+}
+
+impl State {
+    pub async fn build(config: Config, builder: &dyn BackendBuilder) -> Result<Self> {
+        Ok(Self {
+            s3: builder.s3(&config.s3).await?,
+            rds: builder.rds(&config.rds).await?,
+            dynamodb: builder.dynamodb(&config.dynamodb).await?,
+            outbox: builder.outbox(&config.outbox)?,
+            idempotency: builder.idempotency(&config.idempotency).await?,
+            config: Arc::new(config),
+        })
+    }
+
+    //Builds the state for `config`, reusing the backends whose sections are unchanged.
+    //Nothing is replaced unless every changed backend is built successfully.
+    //The outbox is always kept, as its pending entries must not be left behind.
+    pub async fn rebuild(&self, config: Config, builder: &dyn BackendBuilder) -> Result<Self> {
+        let s3 = if config.s3 == self.config.s3 {
+            self.s3.clone()
+        } else {
+            builder.s3(&config.s3).await?
+        };
+        let rds = if config.rds == self.config.rds {
+            self.rds.clone()
+        } else {
+            builder.rds(&config.rds).await?
+        };
+        let dynamodb = if config.dynamodb == self.config.dynamodb {
+            self.dynamodb.clone()
+        } else {
+            builder.dynamodb(&config.dynamodb).await?
+        };
+        //Only the store matters; `window_sec` is read from `config` on each request.
+        let idempotency = if config.idempotency.table_name == self.config.idempotency.table_name {
+            self.idempotency.clone()
+        } else {
+            builder.idempotency(&config.idempotency).await?
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            s3,
+            rds,
+            dynamodb,
+            outbox: self.outbox.clone(),
+            idempotency,
+        })
+    }
+}
@@ -1,4 +1,4 @@
-use std::{error::Error, time::SystemTime};
+use std::error::Error;
 
 use lambda_runtime::LambdaEvent;
 use serde::{Deserialize, Serialize};
@@ -61,13 +61,7 @@ async fn handler(req: LambdaEvent<serde_json::Value>) -> Result<Response, Respon
     let config = aws_config::load_from_env().await;
     let s3_client = aws_sdk_s3::Client::new(&config);
 
-    let filename = format!(
-        "{}.txt",
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-    );
+    let filename = format!("{}.txt", unique_id::generate());
 
     let res = s3_client
         .put_object()
@@ -0,0 +1,122 @@
+//Monotonic, sortable unique IDs in the format of ULID (ref: |https://github.com/ulid/spec|).
+//An ID is 48 bits of epoch millis followed by 80 random bits, written as 26 characters of Crockford's base32,
+//so IDs sort by the time they are created.
+//Within a process, an ID created in the same millisecond as the previous one (or after the clock went back)
+//is the previous one plus one, so IDs never collide and are strictly increasing.
+//Across processes, the random bits make collisions practically impossible.
+
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+const LEN: usize = 26;
+
+const RANDOM_BITS: u32 = 80;
+
+/*-------------------------------------*/
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn encode(id: u128) -> String {
+    (0..LEN)
+        .rev()
+        .map(|i| ALPHABET[((id >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+pub struct Generator {
+    last: Mutex<u128>,
+}
+
+impl Generator {
+    pub const fn new() -> Self {
+        Self {
+            last: Mutex::new(0),
+        }
+    }
+
+    pub fn generate(&self) -> String {
+        self.generate_at(now_millis())
+    }
+
+    fn generate_at(&self, millis: u64) -> String {
+        let mut last = self.last.lock().unwrap();
+        let millis = (millis as u128) & ((1 << 48) - 1);
+        *last = if millis > *last >> RANDOM_BITS {
+            (millis << RANDOM_BITS) | (fastrand::u128(..) >> (128 - RANDOM_BITS))
+        } else {
+            //When the random bits overflow, the carry moves the timestamp forward by a millisecond.
+            *last + 1
+        };
+        encode(*last)
+    }
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+//Creates an ID with the generator shared by the whole process.
+pub fn generate() -> String {
+    static GENERATOR: Generator = Generator::new();
+    GENERATOR.generate()
+}
+
+/*-------------------------------------*/
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test01() {
+        assert_eq!("00000000000000000000000000", encode(0));
+        assert_eq!("7ZZZZZZZZZZZZZZZZZZZZZZZZZ", encode(u128::MAX));
+        assert_eq!("0000000001000000000000000Z", encode((1 << 80) | 31));
+
+        let generator = Generator::new();
+        let a = generator.generate_at(1_678_969_418_940);
+        assert_eq!(LEN, a.len());
+        assert_eq!("01GVN60D5W", &a[..10]);
+
+        //The same millisecond and a clock going back both still produce larger IDs.
+        let b = generator.generate_at(1_678_969_418_940);
+        let c = generator.generate_at(1_678_969_418_000);
+        let d = generator.generate_at(1_678_969_418_941);
+        assert!(a < b && b < c && c < d);
+        assert_eq!(&a[..10], &c[..10]);
+        assert_eq!("01GVN60D5X", &d[..10]);
+    }
+
+    //IDs created concurrently never collide, and those of each thread are strictly increasing.
+    #[test]
+    fn test02() {
+        const NUM_THREAD: usize = 8;
+        const NUM_ID: usize = 10000;
+
+        let handles = (0..NUM_THREAD)
+            .map(|_| thread::spawn(|| (0..NUM_ID).map(|_| generate()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        let mut ids = HashSet::new();
+        for handle in handles {
+            let v = handle.join().unwrap();
+            assert!(v.windows(2).all(|w| w[0] < w[1]));
+            ids.extend(v);
+        }
+        assert_eq!(NUM_THREAD * NUM_ID, ids.len());
+    }
+}
+
+/*-------------------------------------*/